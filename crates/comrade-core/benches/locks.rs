@@ -0,0 +1,117 @@
+//! Benchmarks for the lock paths and values. Run with `cargo bench -p comrade-core`.
+use comrade_core::testing::{gen_keypair, pubkey_hex, sign, Codec};
+use comrade_core::{
+    Comrade, ComradeBuilder, ContextPairs, Current, Pairs, Proposed, Unlocked, Value,
+};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const ENTRY: &[u8] = b"for great justice, move every zig!";
+
+const UNLOCK: &str = r#"
+    push("/entry/");
+    push("/entry/proof");
+"#;
+
+/// The current store holding a fresh pubkey, and the proposed store holding an entry signed
+/// with the matching private key
+fn signed_stores() -> (ContextPairs, ContextPairs) {
+    let mk = gen_keypair(Codec::Ed25519Priv);
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey_hex(&mk)).unwrap().into());
+
+    let mut proposed = ContextPairs::default();
+    proposed.put("/entry/", &ENTRY.to_vec().into());
+    proposed.put("/entry/proof", &sign(&mk, ENTRY).into());
+
+    (current, proposed)
+}
+
+/// Unlocks the signed stores, ready to run signature locks
+fn unlocked(
+    current: ContextPairs,
+    proposed: ContextPairs,
+    builder: impl FnOnce(&mut ComradeBuilder<ContextPairs, ContextPairs>),
+) -> Comrade<Unlocked, ContextPairs, ContextPairs> {
+    let mut comrade = ComradeBuilder::new(UNLOCK, Current(current), Proposed(proposed));
+    builder(&mut comrade);
+    comrade.try_unlock().unwrap()
+}
+
+/// The native single-signature fast path against the same check run as a Rhai script
+fn bench_try_lock_signature(c: &mut Criterion) {
+    let (current, proposed) = signed_stores();
+    let unlocked = unlocked(current, proposed, |_| {});
+
+    let mut group = c.benchmark_group("single_signature");
+    group.bench_function("try_lock", |b| {
+        b.iter(|| unlocked.try_lock(r#"check_signature("/pubkey", "/entry/")"#))
+    });
+    group.bench_function("try_lock_signature", |b| {
+        b.iter(|| unlocked.try_lock_signature("/pubkey", "/entry/"))
+    });
+    group.finish();
+}
+
+/// Lock attempts on large stores, deep cloning them for every attempt against sharing them
+fn bench_shared_stores(c: &mut Criterion) {
+    let (mut current, mut proposed) = signed_stores();
+    for i in 0..10_000 {
+        let filler = vec![0u8; 1024].into();
+        current.put(&format!("/filler/{i}"), &filler);
+        proposed.put(&format!("/filler/{i}"), &filler);
+    }
+
+    let deep = unlocked(current.clone(), proposed.clone(), |_| {});
+    let shared = unlocked(current, proposed, |builder| {
+        builder.with_shared_stores();
+    });
+
+    let lock = r#"check_signature("/pubkey", "/entry/")"#;
+    let mut group = c.benchmark_group("large_stores");
+    group.bench_function("deep", |b| b.iter(|| deep.try_lock(lock)));
+    group.bench_function("shared", |b| b.iter(|| shared.try_lock(lock)));
+    group.finish();
+}
+
+/// A lock repeating the same failing signature check, which is only verified once, against a
+/// lock running it once. A check that verifies pops the signature, so only failures repeat.
+fn bench_signature_cache(c: &mut Criterion) {
+    let (current, mut proposed) = signed_stores();
+    proposed.put("/other/", &b"not the signed entry".to_vec().into());
+    let unlocked = unlocked(current, proposed, |_| {});
+
+    let mut group = c.benchmark_group("signature_cache");
+    group.bench_function("one_check", |b| {
+        b.iter(|| unlocked.try_lock(r#"check_signature("/pubkey", "/other/")"#))
+    });
+    group.bench_function("repeated_check", |b| {
+        b.iter(|| {
+            unlocked.try_lock(
+                r#"check_signature("/pubkey", "/other/") || check_signature("/pubkey", "/other/")"#,
+            )
+        })
+    });
+    group.finish();
+}
+
+/// Cloning a 1 MiB [Value::Bin], which shares its data, against cloning the `Vec<u8>` it held
+/// before
+fn bench_bin_clone(c: &mut Criterion) {
+    let bytes = vec![0u8; 1 << 20];
+    let value = Value::from(bytes.clone());
+
+    let mut group = c.benchmark_group("bin_clone");
+    group.bench_function("vec", |b| b.iter(|| black_box(&bytes).clone()));
+    group.bench_function("value", |b| b.iter(|| black_box(&value).clone()));
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_try_lock_signature,
+    bench_shared_stores,
+    bench_signature_cache,
+    bench_bin_clone
+);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+//! Parallel batch verification of many independent entries
+use crate::{ComradeError, Current, Pairable, Proposed, Value};
+use rayon::prelude::*;
+
+/// A single entry to verify with [verify_batch]
+#[derive(Debug, Clone)]
+pub struct EntrySpec<C: Pairable, P: Pairable> {
+    /// The unlock script, run once
+    pub unlock: String,
+    /// The lock scripts, tried in order until one succeeds
+    pub locks: Vec<String>,
+    /// The current key-value store
+    pub current: Current<C>,
+    /// The proposed key-value store
+    pub proposed: Proposed<P>,
+}
+
+/// Verifies each entry in parallel, returning the results in the same order as the entries.
+///
+/// Each entry gets its own Rhai [rhai::Engine] and context, so no state is shared between
+/// threads. An entry's result is the value of the first lock that succeeds, or the result of
+/// the last lock tried if none succeed.
+pub fn verify_batch<C, P>(entries: Vec<EntrySpec<C, P>>) -> Vec<Result<Option<Value>, ComradeError>>
+where
+    C: Pairable + Send + Sync + 'static,
+    P: Pairable + Send + Sync + 'static,
+{
+    entries.into_par_iter().map(verify_entry).collect()
+}
+
+/// Unlocks the entry, then tries each of its locks
+fn verify_entry<C, P>(entry: EntrySpec<C, P>) -> Result<Option<Value>, ComradeError>
+where
+    C: Pairable + Send + Sync + 'static,
+    P: Pairable + Send + Sync + 'static,
+{
+    crate::verify(
+        entry.current.0,
+        entry.proposed.0,
+        &entry.unlock,
+        &entry.locks,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ContextPairs, Pairs};
+
+    fn entry(answer: &str) -> EntrySpec<ContextPairs, ContextPairs> {
+        let mut current = ContextPairs::default();
+        current.put("/expected", &"zig".into());
+
+        let mut proposed = ContextPairs::default();
+        proposed.put("/answer", &answer.into());
+
+        EntrySpec {
+            unlock: r#"push("/answer")"#.to_string(),
+            locks: vec![
+                r#"check_preimage("/hash")"#.to_string(),
+                r#"check_eq("/expected")"#.to_string(),
+            ],
+            current: Current(current),
+            proposed: Proposed(proposed),
+        }
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let entries = vec![entry("zig"), entry("zag"), entry("zig")];
+
+        let results = verify_batch(entries);
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], Ok(Some(Value::Success(0))));
+        assert!(matches!(results[1], Ok(Some(Value::Failure(_)))));
+        assert_eq!(results[2], Ok(Some(Value::Success(0))));
+    }
+}
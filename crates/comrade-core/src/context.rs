@@ -2,23 +2,140 @@
 use super::Pairable;
 use crate::storage::pairs::Pairs;
 use crate::storage::stack::Stack as _;
+use crate::storage::value::unordered_hash;
 use crate::storage::{stack::Stk, value::Value};
+use crate::verifier::Verifier;
 use crate::Either;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use multicodec::Codec;
 use multihash::{mh, Multihash};
 use multikey::{Multikey, Views as _};
 use multisig::Multisig;
 use multiutil::CodecInfo;
+use sha2::{Digest, Sha256, Sha512};
+use sha3::Keccak256;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
-use tracing::{debug, warn};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, trace, warn};
+
+/// Failure message when a SUCCESS or FAILURE marker is found where a check expects data
+const UNEXPECTED_CONTROL_VALUE: &str = "unexpected control value on parameter stack";
+
+/// The most parameters any check pops off the stack, which [Context::guarded] restores on a panic
+const MAX_CHECK_POPS: usize = 2;
+
+/// Default maximum number of nested domains pushed with push_domain()
+pub const DEFAULT_MAX_DOMAIN_DEPTH: usize = 16;
+
+/// The key-value store `check_signature` looks up the signed message from
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum MessageSource {
+    /// Look up the message in the [Current] key-value store, alongside the keys
+    Current,
+    /// Look up the message in the [Proposed] key-value store (default)
+    #[default]
+    Proposed,
+}
+
+/// The current time in seconds since the unix epoch, from the system clock. The default clock
+/// `check_after` and `check_before` compare against.
+pub fn system_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// The clock a [Context] compares timestamps against, returning seconds since the unix epoch.
+/// Defaults to [system_clock].
+#[derive(Clone)]
+pub(crate) struct Clock(pub(crate) Arc<dyn Fn() -> u64 + Send + Sync>);
+
+impl Clock {
+    /// The current time, in seconds since the unix epoch
+    fn now(&self) -> u64 {
+        (self.0)()
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock(Arc::new(system_clock))
+    }
+}
+
+impl std::fmt::Debug for Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Clock")
+    }
+}
+
+/// How `check_signature` decodes a signed message stored as a [Value::Str].
+/// [Value::Bin] messages are always used as they are.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum MessageEncoding {
+    /// Use the UTF-8 bytes of the string (default)
+    #[default]
+    Raw,
+    /// Hex decode the string
+    Hex,
+    /// Decode the string as standard, padded base64
+    Base64,
+}
 
 /// A simple key-value store that implement [Pairs] uses a HashMap to store the key-value pairs.
 /// Used for examples and testing.
-#[derive(Clone, Default, Debug)]
+///
+/// Two stores are equal when they hold the same key-value pairs, whatever order they were
+/// inserted in.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub struct ContextPairs {
     pairs: HashMap<String, Value>,
 }
 
+/// Hashes independently of insertion order. Every key-value pair is hashed on each call, so
+/// hashing costs time linear in the size of the store, values included.
+impl Hash for ContextPairs {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.pairs.len().hash(state);
+        unordered_hash(self.pairs.iter()).hash(state);
+    }
+}
+
+impl ContextPairs {
+    /// Iterate over the key-value pairs, in arbitrary order
+    pub fn iter(&self) -> std::collections::hash_map::Iter<'_, String, Value> {
+        self.pairs.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ContextPairs {
+    type Item = (&'a String, &'a Value);
+    type IntoIter = std::collections::hash_map::Iter<'a, String, Value>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.pairs.iter()
+    }
+}
+
+impl From<HashMap<String, Value>> for ContextPairs {
+    fn from(pairs: HashMap<String, Value>) -> Self {
+        ContextPairs { pairs }
+    }
+}
+
+impl FromIterator<(String, Value)> for ContextPairs {
+    fn from_iter<I: IntoIterator<Item = (String, Value)>>(iter: I) -> Self {
+        ContextPairs {
+            pairs: iter.into_iter().collect(),
+        }
+    }
+}
+
 impl Pairs for ContextPairs {
     fn get(&self, key: &str) -> Option<Value> {
         self.pairs.get(key).cloned()
@@ -27,20 +144,45 @@ impl Pairs for ContextPairs {
     fn put(&mut self, key: &str, value: &Value) -> Option<Value> {
         self.pairs.insert(key.to_string(), value.clone())
     }
+
+    fn compare_and_put(&mut self, key: &str, expected: Option<&Value>, new: &Value) -> bool {
+        if self.pairs.get(key) != expected {
+            return false;
+        }
+        self.pairs.insert(key.to_string(), new.clone());
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    fn keys(&self) -> Option<Vec<String>> {
+        Some(self.pairs.keys().cloned().collect())
+    }
+
+    fn clone_box(&self) -> Option<Box<dyn Pairs + Send + Sync>> {
+        Some(Box::new(self.clone()))
+    }
 }
 
 #[derive(Debug)]
 pub struct Context<C: Pairable, P: Pairable> {
     /// The current key-value store for the key-pairs. Can be any type that implements the [Pairs] trait
-    pub(crate) current: Either<C, P>,
+    pub(crate) current: Arc<Either<C, P>>,
 
     /// The proposed key-value store for the Context keypairs
-    pub proposed: P,
+    pub proposed: Arc<P>,
 
-    /// The number of times a check_* operation has been executed
+    /// The number of times a check_* operation has been executed. Saturates at [usize::MAX].
     pub check_count: usize,
 
-    /// The Return stack
+    /// The number of check_* operations that have run, whether they succeeded or failed.
+    /// Saturates at [usize::MAX].
+    pub checks_run: usize,
+
+    /// The Return stack. Every check pushes its SUCCESS or FAILURE marker in evaluation order,
+    /// so the top is always the result of the last evaluated check.
     pub rstack: Stk,
 
     /// The Parameters stack
@@ -48,21 +190,126 @@ pub struct Context<C: Pairable, P: Pairable> {
 
     /// Optional domain segment of the /branch/leaf/ key-path. Defaults to "/".
     pub domain: String,
+
+    /// Previous domains saved by push_domain(), restored by pop_domain()
+    pub(crate) domains: Vec<String>,
+
+    /// Maximum number of nested push_domain() calls. Defaults to [DEFAULT_MAX_DOMAIN_DEPTH].
+    pub(crate) max_domain_depth: usize,
+
+    /// Optional maximum size, in bytes, of a single value pushed onto the parameter stack
+    pub(crate) max_value_size: Option<usize>,
+
+    /// Where `check_signature` looks up the signed message. Defaults to [MessageSource::Proposed].
+    pub(crate) message_source: MessageSource,
+
+    /// How `check_signature` decodes string messages. Defaults to [MessageEncoding::Raw].
+    pub(crate) message_encoding: MessageEncoding,
+
+    /// Read-only constants available to both unlock and lock scripts
+    pub(crate) constants: Vec<(String, Value)>,
+
+    /// Whether clones share the current and proposed stores instead of deep cloning them.
+    /// Defaults to false.
+    pub(crate) shared_stores: bool,
+
+    /// Whether [Value::Str] keys are hex-decoded when binary data is expected. Defaults to false.
+    pub(crate) lenient_hex: bool,
+
+    /// Whether pushing the same key more than once records a warning. Defaults to false.
+    pub(crate) warn_duplicate_pushes: bool,
+
+    /// Optional custom signature [Verifier]. Defaults to the built-in multikey verification.
+    pub(crate) verifier: Option<Arc<dyn Verifier>>,
+
+    /// The keys pushed so far, tracked when duplicate push warnings are enabled
+    pub(crate) pushed: Vec<String>,
+
+    /// Whether every call a script makes to a registered function is traced. Defaults to false.
+    pub(crate) call_tracing: bool,
+
+    /// Whether script given keys are trimmed, and empty keys rejected. Defaults to false.
+    pub(crate) normalize_keys: bool,
+
+    /// Whether unlocking with an empty proposed store is an error rather than a warning.
+    /// Defaults to false.
+    pub(crate) require_proposed: bool,
+
+    /// Non-fatal warnings recorded while running scripts, such as duplicate pushes
+    pub warnings: Vec<String>,
+
+    /// Labeled results scripts recorded with report(), in the order they were reported
+    pub(crate) reports: Vec<(String, Value)>,
+
+    /// The clock `check_after` and `check_before` compare against, in seconds since the unix
+    /// epoch. Defaults to [system_clock].
+    pub(crate) clock: Clock,
+
+    /// Whether a single script both pushes proofs and checks them, so push() reads from the
+    /// proposed store while the checks read from the current store. Defaults to false.
+    pub(crate) combined: bool,
+
+    /// Whether [crate::Comrade::dump] leaves out the data of binary and string values. Defaults
+    /// to true.
+    pub(crate) redact: bool,
+
+    /// Results of check_signature verifications, keyed by pubkey, message and signature, so
+    /// identical checks in different branches of a lock are only verified once and fail with
+    /// the same error each time. Never cloned, so each lock attempt starts empty.
+    pub(crate) verified: HashMap<(Value, Arc<[u8]>, Value), Result<(), String>>,
 }
 
 impl<C: Pairable, P: Pairable> Clone for Context<C, P> {
     fn clone(&self) -> Self {
+        self.clone_sharing(self.shared_stores)
+    }
+}
+
+impl<C: Pairable, P: Pairable> Context<C, P> {
+    /// Clones the context, sharing its stores with the clone if `share_stores` is set, or deep
+    /// cloning them otherwise. The stacks and counters are always cloned.
+    pub(crate) fn clone_sharing(&self, share_stores: bool) -> Self {
         Context {
-            current: self.current.clone(),
-            proposed: self.proposed.clone(),
+            current: clone_store(&self.current, share_stores),
+            proposed: clone_store(&self.proposed, share_stores),
             check_count: self.check_count,
+            checks_run: self.checks_run,
             rstack: self.rstack.clone(),
             pstack: self.pstack.clone(),
             domain: self.domain.clone(),
+            domains: self.domains.clone(),
+            max_domain_depth: self.max_domain_depth,
+            max_value_size: self.max_value_size,
+            message_source: self.message_source,
+            message_encoding: self.message_encoding,
+            constants: self.constants.clone(),
+            shared_stores: self.shared_stores,
+            lenient_hex: self.lenient_hex,
+            warn_duplicate_pushes: self.warn_duplicate_pushes,
+            require_proposed: self.require_proposed,
+            normalize_keys: self.normalize_keys,
+            call_tracing: self.call_tracing,
+            verifier: self.verifier.clone(),
+            pushed: self.pushed.clone(),
+            warnings: self.warnings.clone(),
+            reports: self.reports.clone(),
+            clock: self.clock.clone(),
+            combined: self.combined,
+            redact: self.redact,
+            verified: HashMap::new(),
         }
     }
 }
 
+/// Clones a store for a cloned [Context], either sharing it or deep cloning it
+fn clone_store<T: Clone>(store: &Arc<T>, share: bool) -> Arc<T> {
+    if share {
+        Arc::clone(store)
+    } else {
+        Arc::new(store.as_ref().clone())
+    }
+}
+
 /// NewType wrapper to ensure Current key-value is used for the Context
 #[derive(Debug, Default, Clone)]
 pub struct Current<P: Pairable>(pub P);
@@ -76,12 +323,25 @@ impl<P: Pairable> Deref for Current<P> {
     }
 }
 
+impl<P: Pairable> Current<P> {
+    /// Unwraps the inner key-value store
+    pub fn into_inner(self) -> P {
+        self.0
+    }
+}
+
 impl<P: Pairable> From<P> for Current<P> {
     fn from(p: P) -> Self {
         Current(p)
     }
 }
 
+impl From<HashMap<String, Value>> for Current<ContextPairs> {
+    fn from(pairs: HashMap<String, Value>) -> Self {
+        Current(pairs.into())
+    }
+}
+
 impl<P: Pairable> std::ops::DerefMut for Current<P> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
@@ -100,12 +360,25 @@ impl<P: Pairable> Deref for Proposed<P> {
     }
 }
 
+impl<P: Pairable> Proposed<P> {
+    /// Unwraps the inner key-value store
+    pub fn into_inner(self) -> P {
+        self.0
+    }
+}
+
 impl<P: Pairable> From<P> for Proposed<P> {
     fn from(p: P) -> Self {
         Proposed(p)
     }
 }
 
+impl From<HashMap<String, Value>> for Proposed<ContextPairs> {
+    fn from(pairs: HashMap<String, Value>) -> Self {
+        Proposed(pairs.into())
+    }
+}
+
 impl<P: Pairable> std::ops::DerefMut for Proposed<P> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
@@ -117,84 +390,146 @@ impl<C: Pairable, P: Pairable> Context<C, P> {
     /// which are bound by both [Pairable].
     pub(crate) fn new(current: Either<C, P>, proposed: P) -> Self {
         Context {
-            current,
-            proposed,
+            current: Arc::new(current),
+            proposed: Arc::new(proposed),
             check_count: 0,
+            checks_run: 0,
             rstack: Default::default(),
             pstack: Default::default(),
             domain: "/".to_string(),
+            domains: Vec::new(),
+            max_domain_depth: DEFAULT_MAX_DOMAIN_DEPTH,
+            max_value_size: None,
+            message_source: MessageSource::default(),
+            message_encoding: MessageEncoding::default(),
+            constants: Vec::new(),
+            shared_stores: false,
+            lenient_hex: false,
+            warn_duplicate_pushes: false,
+            require_proposed: false,
+            normalize_keys: false,
+            call_tracing: false,
+            verifier: None,
+            pushed: Vec::new(),
+            warnings: Vec::new(),
+            reports: Vec::new(),
+            clock: Clock::default(),
+            combined: false,
+            redact: true,
+            verified: HashMap::new(),
         }
     }
 
+    /// The number of key-value pairs in the current store
+    pub fn current_len(&self) -> usize {
+        self.current.len()
+    }
+
+    /// The number of key-value pairs in the proposed store
+    pub fn proposed_len(&self) -> usize {
+        self.proposed.len()
+    }
+
     /// Check the signature of the given key str
     pub fn check_signature(&mut self, key: &str, msg: &str) -> bool {
-        // lookup the keypair for this key
-        let pubkey = {
-            match &self.current.get(key) {
-                Some(Value::Bin { hint: _, data }) => match Multikey::try_from(data.as_ref()) {
-                    Ok(mk) => mk,
-                    Err(e) => {
-                        warn!("check_signature: error decoding multikey: {e}");
-                        return self.check_fail(&e.to_string());
-                    }
-                },
-                Some(_) => {
-                    warn!("check_signature: unexpected value type associated with {key}");
-                    return self
-                        .check_fail(&format!("unexpected value type associated with {key}"));
-                }
-                None => {
-                    warn!("check_signature: no multikey associated with {key}");
-                    return self.check_fail(&format!("no multikey associated with {key}"));
-                }
+        self.check_signature_over(key, msg, |context| context.signed_message(msg))
+    }
+
+    /// Check the signature on the stack over a canonical encoding of the whole proposed store,
+    /// [Pairs::canonical_bytes], rather than over one stored message. `proof_key`, the key the
+    /// signature itself is stored under, is left out of the encoding, since a signature can't
+    /// cover itself. The signature is popped if it verifies.
+    pub fn check_signature_over_proposed(&mut self, key: &str, proof_key: &str) -> bool {
+        self.check_signature_over(key, "the proposed store", |context| {
+            let proof_key = context.normalize_key(proof_key)?;
+            context
+                .proposed
+                .canonical_bytes(&[proof_key])
+                .map(Into::into)
+                .ok_or_else(|| "the proposed store can't list its keys".to_string())
+        })
+    }
+
+    /// Check the signature on the stack of the given key str over the message, which is looked
+    /// up after the key. `msg` names the message in logs.
+    fn check_signature_over(
+        &mut self,
+        key: &str,
+        msg: &str,
+        message: impl FnOnce(&Self) -> Result<Arc<[u8]>, String>,
+    ) -> bool {
+        let key = match self.normalize_key(key) {
+            Ok(key) => key,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        if let Some(verifier) = self.verifier.clone() {
+            return self.check_signature_with(verifier.as_ref(), key, msg, message);
+        }
+
+        // lookup the keypair for this key, keeping the stored value for the cache key
+        let (stored, pubkey) = match self.stored_multikey_value(key) {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!("check_signature: {e}");
+                return self.check_fail(&e);
             }
         };
 
         // look up the message that was signed
-        let message = {
-            match self.proposed.get(msg) {
-                Some(Value::Bin { hint: _, data }) => data,
-                Some(Value::Str { hint: _, data }) => data.as_bytes().to_vec(),
-                Some(_) => {
-                    warn!("check_signature: unexpected value type associated with {msg}");
-                    return self
-                        .check_fail(&format!("unexpected value type associated with {msg}"));
-                }
-                None => {
-                    warn!("check_signature: no message associated with {msg}");
-                    return self.check_fail(&format!("no message associated with {msg}"));
-                }
+        let message = match message(self) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("check_signature: {e}");
+                return self.check_fail(&e);
             }
         };
 
         // make sure we have at least one parameter on the stack
-        if self.pstack.len() < 1 {
-            return self.check_fail(&format!(
-                "not enough parameters ({}) on the stack for check_signature ({key}, {msg})",
-                self.pstack.len()
-            ));
+        if !self.require_pstack(1) {
+            return false;
         }
 
         // peek at the top item and verify that it is a Multisig
-        let sig = {
-            match self.pstack.top() {
-                Some(Value::Bin { hint: _, data }) => match Multisig::try_from(data.as_ref()) {
-                    Ok(sig) => sig,
-                    Err(e) => return self.check_fail(&e.to_string()),
-                },
-                _ => return self.check_fail("no multisig on stack"),
+        let Some(top) = self.pstack.top() else {
+            return self.check_fail("no multisig on stack");
+        };
+        let sig = match &top {
+            Value::Bin { hint: _, data } => match Multisig::try_from(data.as_ref()) {
+                Ok(sig) => sig,
+                Err(e) => return self.check_fail(&e.to_string()),
+            },
+            Value::Success(_) | Value::Failure(_) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
             }
+            _ => return self.check_fail("no multisig on stack"),
         };
 
-        // get the verify view
-        let verify_view = match pubkey.verify_view() {
-            Ok(v) => v,
-            Err(e) => return self.check_fail(&e.to_string()),
+        let cache_key = (stored, message.clone(), top);
+        let verified = match self.verified.get(&cache_key).cloned() {
+            Some(verified) => {
+                debug!("check_signature({key}, {msg}) reusing cached verification");
+                verified
+            }
+            None => {
+                // get the verify view
+                let verify_view = match pubkey.verify_view() {
+                    Ok(v) => v,
+                    Err(e) => return self.check_fail(&e.to_string()),
+                };
+
+                // verify the signature
+                let verified = verify_view
+                    .verify(&sig, Some(message.as_ref()))
+                    .map(|_| ())
+                    .map_err(|e| e.to_string());
+                self.verified.insert(cache_key, verified.clone());
+                verified
+            }
         };
 
-        // verify the signature
-        match verify_view.verify(&sig, Some(message.as_ref())) {
-            Ok(_) => {
+        match verified {
+            Ok(()) => {
                 // the signature verification worked so pop the signature arg off
                 // of the stack before continuing
                 self.pstack.pop();
@@ -202,166 +537,1727 @@ impl<C: Pairable, P: Pairable> Context<C, P> {
             }
             Err(e) => {
                 warn!("check_signature({key}, {msg}) -> false");
-                self.check_fail(&e.to_string())
+                self.check_fail(&e)
             }
         }
     }
 
-    /// Check the preimage of the given key
-    pub fn check_preimage(&mut self, key: String) -> bool {
-        // look up the hash and try to decode it
-        let hash = {
-            match self.current.get(&key) {
-                Some(Value::Bin { hint: _, data }) => match Multihash::try_from(data.as_ref()) {
-                    Ok(hash) => hash,
-                    Err(e) => return self.check_fail(&e.to_string()),
-                },
-                Some(_) => {
-                    return self
-                        .check_fail(&format!("unexpected value type associated with {}", key))
-                }
-                None => return self.check_fail(&format!("kvp missing key: {key}")),
+    /// Check the signature on the stack with a custom [Verifier], passing it the key, message
+    /// and signature values as stored
+    fn check_signature_with(
+        &mut self,
+        verifier: &dyn Verifier,
+        key: &str,
+        msg: &str,
+        message: impl FnOnce(&Self) -> Result<Arc<[u8]>, String>,
+    ) -> bool {
+        let Some(pubkey) = self.current.get(key) else {
+            let e = self.swapped_hint(format!("no key associated with {key}"), key);
+            warn!("check_signature: {e}");
+            return self.check_fail(&e);
+        };
+
+        let message = match message(self) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("check_signature: {e}");
+                return self.check_fail(&e);
             }
         };
 
-        // make sure we have at least one parameter on the stack
-        if self.pstack.len() < 1 {
-            warn!(
-                "not enough parameters on the stack for check_preimage: {}",
-                self.pstack.len()
-            );
-            return self.check_fail(&format!(
-                "not enough parameters on the stack for check_preimage: {}",
-                self.pstack.len()
-            ));
+        if !self.require_pstack(1) {
+            return false;
         }
 
-        // get the preimage data from the stack
-        let preimage = {
-            match self.pstack.top() {
-                Some(Value::Bin { hint: _, data }) => {
-                    match mh::Builder::new_from_bytes(hash.codec(), data) {
-                        Ok(builder) => match builder.try_build() {
-                            Ok(hash) => hash,
-                            Err(e) => return self.check_fail(&e.to_string()),
-                        },
-                        Err(e) => return self.check_fail(&e.to_string()),
-                    }
-                }
-                Some(Value::Str { hint: _, data }) => {
-                    match mh::Builder::new_from_bytes(hash.codec(), data.as_bytes()) {
-                        Ok(builder) => match builder.try_build() {
-                            Ok(hash) => hash,
-                            Err(e) => return self.check_fail(&e.to_string()),
-                        },
-                        Err(e) => return self.check_fail(&e.to_string()),
-                    }
-                }
-                _ => return self.check_fail("no multihash data on stack"),
+        let sig = match self.pstack.top() {
+            Some(Value::Success(_) | Value::Failure(_)) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
             }
+            Some(sig) => sig,
+            None => return self.check_fail("no signature on stack"),
         };
 
-        // check that the hashes match
-        if hash == preimage {
-            // the hash check passed so pop the argument from the stack
-            let _ = self.pstack.pop();
-            self.succeed()
-        } else {
-            // the hashes don't match
-            self.check_fail("preimage doesn't match")
+        let cache_key = (pubkey, message, sig);
+        let verified = match self.verified.get(&cache_key).cloned() {
+            Some(verified) => {
+                debug!("check_signature({key}, {msg}) reusing cached verification");
+                verified
+            }
+            None => {
+                let (pubkey, message, sig) = &cache_key;
+                let verified = match verifier.verify(pubkey, message, sig) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err("signature did not verify".to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                self.verified.insert(cache_key, verified.clone());
+                verified
+            }
+        };
+
+        match verified {
+            Ok(()) => {
+                self.pstack.pop();
+                self.succeed()
+            }
+            Err(e) => {
+                warn!("check_signature({key}, {msg}) -> false");
+                self.check_fail(&e)
+            }
         }
     }
 
-    /// Verifies the top of the stack matches the value associated with the key
-    pub fn check_eq(&mut self, key: &str) -> bool {
-        // look up the value associated with the key
-        let value = {
-            match self.current.get(key) {
-                Some(Value::Bin { hint: _, data }) => data,
-                Some(Value::Str { hint: _, data }) => data.as_bytes().to_vec(),
-                _ => {
-                    warn!("check_eq: no value associated with {key}");
-                    return self.check_fail(&format!("kvp missing key: {key}"));
-                }
+    /// Check the signature on the stack against each of the given messages in turn, succeeding
+    /// on the first message it verifies against. Useful when a signature may cover either an
+    /// old or a new message serialization. The signature is popped if it verifies.
+    pub fn check_signature_any_msg(&mut self, key: &str, msgs: &[String]) -> bool {
+        let key = match self.normalize_key(key) {
+            Ok(key) => key,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        // lookup the keypair for this key
+        let pubkey = match self.stored_multikey(key) {
+            Ok(mk) => mk,
+            Err(e) => {
+                warn!("check_signature_any_msg: {e}");
+                return self.check_fail(&e);
             }
         };
 
         // make sure we have at least one parameter on the stack
-        if self.pstack.is_empty() {
-            warn!(
-                "not enough parameters on the stack for check_eq: {}",
-                self.pstack.len()
-            );
-            return self.check_fail(&format!(
-                "not enough parameters on the stack for check_eq: {}",
-                self.pstack.len()
-            ));
+        if !self.require_pstack(1) {
+            return false;
         }
 
-        let stack_value = {
-            match self.pstack.top() {
-                Some(Value::Bin { hint: _, data }) => data,
-                Some(Value::Str { hint: _, data }) => data.as_bytes().to_vec(),
-                _ => {
-                    warn!("check_eq: no value on the stack");
-                    return self.check_fail("no value on the stack");
-                }
+        let sig = match self.pstack.top() {
+            Some(Value::Bin { hint: _, data }) => match Multisig::try_from(data.as_ref()) {
+                Ok(sig) => sig,
+                Err(e) => return self.check_fail(&e.to_string()),
+            },
+            Some(Value::Success(_) | Value::Failure(_)) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
             }
+            _ => return self.check_fail("no multisig on stack"),
         };
 
-        // check if equal
-        if value == stack_value {
-            // the values match so pop the argument from the stack
-            let _ = self.pstack.pop();
-            self.succeed()
-        } else {
-            // the values don't match
-            self.check_fail("values don't match")
+        let verify_view = match pubkey.verify_view() {
+            Ok(v) => v,
+            Err(e) => return self.check_fail(&e.to_string()),
+        };
+
+        for msg in msgs {
+            // skip candidates that aren't stored
+            let Ok(message) = self.signed_message(msg) else {
+                continue;
+            };
+            if verify_view.verify(&sig, Some(message.as_ref())).is_ok() {
+                debug!("check_signature_any_msg({key}) verified against {msg}");
+                self.pstack.pop();
+                return self.succeed();
+            }
         }
-    }
 
-    /// Increment the check counter and to push a FAILURE marker on the return stack
-    pub fn check_fail(&mut self, err: &str) -> bool {
-        // update the context check_count
-        self.check_count += 1;
-        // fail
-        self.fail(err)
+        warn!("check_signature_any_msg({key}, {msgs:?}) -> false");
+        self.check_fail("signature did not verify against any message")
     }
 
-    /// Increment the check counter and to push a FAILURE marker on the return stack
-    pub fn fail(&mut self, err: &str) -> bool {
-        // push the FAILURE onto the return stack
-        self.rstack.push(Value::Failure(err.to_string()));
-        false
+    /// Look up the [Multikey] stored under the key in the current store
+    fn stored_multikey(&self, key: &str) -> Result<Multikey, String> {
+        self.stored_multikey_value(key).map(|(_, mk)| mk)
     }
 
-    /// Push a SUCCESS marker onto the return stack
-    pub fn succeed(&mut self) -> bool {
-        // push the SUCCESS marker with the check count
-        self.rstack.push(self.check_count.into());
-        // return that we succeeded
-        true
+    /// Like [Context::stored_multikey], also returning the value as stored
+    fn stored_multikey_value(&self, key: &str) -> Result<(Value, Multikey), String> {
+        let Some(stored) = self.current.get(key) else {
+            return Err(self.swapped_hint(format!("no multikey associated with {key}"), key));
+        };
+        let bytes: Arc<[u8]> = match &stored {
+            Value::Bin { hint: _, data } => Arc::clone(data),
+            Value::Str { hint: _, data } if self.lenient_hex => hex::decode(data)
+                .map_err(|e| format!("error hex decoding {key}: {e}"))?
+                .into(),
+            _ => return Err(format!("unexpected value type associated with {key}")),
+        };
+        let mk = Multikey::try_from(bytes.as_ref()).map_err(|e| e.to_string())?;
+        Ok((stored, mk))
     }
 
-    /// Push the value associated with the key onto the parameter stack
-    pub fn push(&mut self, key: &str) -> bool {
-        // try to look up the key-value pair by key and push the result onto the stack
-        match self.current.get(key) {
-            Some(v) => {
-                self.pstack.push(v.clone());
-                true
-            }
-            None => {
-                warn!("push: no value associated with {key}");
-                self.fail(&format!("kvp missing key: {key}"))
-            }
+    /// Appends a hint to the error when the key is missing from the current store but present
+    /// in the proposed store, the usual sign that the two stores were passed the wrong way round
+    fn swapped_hint(&self, e: String, key: &str) -> String {
+        if self.proposed.get(key).is_some() {
+            format!(
+                "{e}, but the proposed store has it: are the current and proposed stores swapped?"
+            )
+        } else {
+            e
         }
     }
 
-    /// Calculate the full key given the context
-    pub fn branch(&self, key: &str) -> String {
-        let s = format!("{}{}", self.domain, key);
-        debug!("branch({}) -> {}", key, s.as_str());
+    /// Check the signature on the stack using the pubkey pushed above it, rather than a
+    /// pubkey stored under a key. Both are popped if the signature verifies.
+    pub fn check_signature_stackkey(&mut self, msg: &str) -> bool {
+        // look up the message that was signed
+        let message = match self.signed_message(msg) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("check_signature_stackkey: {e}");
+                return self.check_fail(&e);
+            }
+        };
+
+        // make sure we have both the pubkey and the signature on the stack
+        if !self.require_pstack(2) {
+            return false;
+        }
+
+        // the pubkey is on top of the signature
+        let pubkey = match self.pstack.top() {
+            Some(Value::Bin { hint: _, data }) => match Multikey::try_from(data.as_ref()) {
+                Ok(mk) => mk,
+                Err(e) => return self.check_fail(&e.to_string()),
+            },
+            Some(Value::Success(_) | Value::Failure(_)) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
+            }
+            _ => return self.check_fail("no multikey on stack"),
+        };
+
+        let sig = match self.pstack.peek(1) {
+            Some(Value::Bin { hint: _, data }) => match Multisig::try_from(data.as_ref()) {
+                Ok(sig) => sig,
+                Err(e) => return self.check_fail(&e.to_string()),
+            },
+            Some(Value::Success(_) | Value::Failure(_)) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
+            }
+            _ => return self.check_fail("no multisig on stack"),
+        };
+
+        // get the verify view
+        let verify_view = match pubkey.verify_view() {
+            Ok(v) => v,
+            Err(e) => return self.check_fail(&e.to_string()),
+        };
+
+        // verify the signature
+        match verify_view.verify(&sig, Some(message.as_ref())) {
+            Ok(_) => {
+                // pop both the pubkey and the signature off of the stack
+                self.pstack.pop();
+                self.pstack.pop();
+                self.succeed()
+            }
+            Err(e) => {
+                warn!("check_signature_stackkey({msg}) -> false");
+                self.check_fail(&e.to_string())
+            }
+        }
+    }
+
+    /// Check a secp256k1 recoverable signature on the stack by recovering the signer's pubkey,
+    /// rather than verifying against a stored pubkey. The signature is popped if the recovered
+    /// address matches the address stored under `expected_address_key`.
+    ///
+    /// The recovery scheme follows Ethereum:
+    /// - the signature is 65 bytes, `r || s || v`, where `v` is the recovery id (0/1 or 27/28).
+    ///   Any other `v` is rejected.
+    /// - `s` must be in the lower half of the curve order, as in EIP-2. The high-S twin of a
+    ///   signature, with the recovery id flipped, recovers the same signer, so it is rejected to
+    ///   keep signatures from being malleable.
+    /// - the signed digest is the keccak-256 hash of the raw message (no `personal_sign` prefix)
+    /// - the address is the last 20 bytes of the keccak-256 hash of the uncompressed pubkey,
+    ///   without its leading `0x04` byte
+    ///
+    /// The expected address is stored either as 20 raw bytes or as a hex string, with or without
+    /// a `0x` prefix.
+    pub fn check_signature_recover(&mut self, msg: &str, expected_address_key: &str) -> bool {
+        let expected_address_key = match self.normalize_key(expected_address_key) {
+            Ok(expected_address_key) => expected_address_key,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        // look up the address the signer must recover to
+        let expected = match self.current.get(expected_address_key) {
+            Some(Value::Bin { hint: _, data }) => data,
+            Some(Value::Str { hint: _, data }) => {
+                match hex::decode(data.trim_start_matches("0x")) {
+                    Ok(data) => data.into(),
+                    Err(e) => return self.check_fail(&e.to_string()),
+                }
+            }
+            _ => {
+                warn!("check_signature_recover: no address associated with {expected_address_key}");
+                return self.check_fail(&format!("kvp missing key: {expected_address_key}"));
+            }
+        };
+
+        // look up the message that was signed
+        let message = match self.signed_message(msg) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("check_signature_recover: {e}");
+                return self.check_fail(&e);
+            }
+        };
+
+        // make sure we have at least one parameter on the stack
+        if !self.require_pstack(1) {
+            return false;
+        }
+
+        let sig = match self.pstack.top() {
+            Some(Value::Bin { hint: _, data }) if data.len() == 65 => data,
+            Some(Value::Success(_) | Value::Failure(_)) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
+            }
+            _ => return self.check_fail("no recoverable signature on stack"),
+        };
+
+        // only the standard v bytes are accepted
+        let recovery_id = match sig[64] {
+            0 | 27 => RecoveryId::from_byte(0),
+            1 | 28 => RecoveryId::from_byte(1),
+            _ => None,
+        };
+        let (signature, recovery_id) = match (Signature::from_slice(&sig[..64]), recovery_id) {
+            (Ok(signature), Some(recovery_id)) => (signature, recovery_id),
+            _ => return self.check_fail("invalid recoverable signature"),
+        };
+
+        // reject the high-S twin of a low-S signature, which would verify too
+        if signature.normalize_s().is_some() {
+            warn!("check_signature_recover: high-S signature");
+            return self.check_fail("non-canonical high-S signature");
+        }
+
+        let digest = Keccak256::digest(&message);
+        let pubkey = match VerifyingKey::recover_from_prehash(&digest, &signature, recovery_id) {
+            Ok(pubkey) => pubkey,
+            Err(e) => return self.check_fail(&e.to_string()),
+        };
+
+        // derive the address from the uncompressed pubkey, skipping the 0x04 tag
+        let point = pubkey.to_encoded_point(false);
+        let hash = Keccak256::digest(&point.as_bytes()[1..]);
+
+        if hash[12..] == expected[..] {
+            // pop the signature off of the stack
+            self.pstack.pop();
+            self.succeed()
+        } else {
+            warn!("check_signature_recover({msg}, {expected_address_key}) -> false");
+            self.check_fail("recovered address does not match")
+        }
+    }
+
+    /// Look up the signed message associated with the key, from the [MessageSource] store
+    fn signed_message(&self, msg: &str) -> Result<Arc<[u8]>, String> {
+        let msg = self.normalize_key(msg)?;
+        let value = match self.message_source {
+            MessageSource::Current => self.current.get(msg),
+            MessageSource::Proposed => self.proposed.get(msg),
+        };
+        match value {
+            Some(Value::Bin { hint: _, data }) => Ok(data),
+            Some(Value::Str { hint: _, data }) => match self.message_encoding {
+                MessageEncoding::Raw => Ok(data.as_bytes().into()),
+                MessageEncoding::Hex => hex::decode(data)
+                    .map(Into::into)
+                    .map_err(|e| format!("error hex decoding {msg}: {e}")),
+                MessageEncoding::Base64 => STANDARD
+                    .decode(data)
+                    .map(Into::into)
+                    .map_err(|e| format!("error base64 decoding {msg}: {e}")),
+            },
+            Some(_) => Err(format!("unexpected value type associated with {msg}")),
+            None => Err(format!("no message associated with {msg}")),
+        }
+    }
+
+    /// Check the signature like [Context::check_signature], and on success push the sha2-256
+    /// [Multihash] of the verified message onto the parameter stack, for the next check to
+    /// consume, such as `check_eq` against a stored hash.
+    pub fn check_signature_chain(&mut self, key: &str, msg: &str) -> bool {
+        // hash before verifying, so a hashing error fails the one check instead of following
+        // a success
+        let hash = match self.signed_message(msg) {
+            Ok(message) => match mh::Builder::new_from_bytes(Codec::Sha2256, &message[..]) {
+                Ok(builder) => match builder.try_build() {
+                    Ok(hash) => hash,
+                    Err(e) => return self.check_fail(&e.to_string()),
+                },
+                Err(e) => return self.check_fail(&e.to_string()),
+            },
+            // check_signature can't verify without the message, and reports why
+            Err(_) => return self.check_signature(key, msg),
+        };
+
+        if !self.check_signature(key, msg) {
+            return false;
+        }
+
+        self.pstack.push(Into::<Vec<u8>>::into(hash).into());
+        true
+    }
+
+    /// Check the signature of the given key str, resolved through branch() so that the key is
+    /// scoped to the current domain. The key is normalized before it is branched.
+    pub fn check_signature_branch(&mut self, key: &str, msg: &str) -> bool {
+        let key = match self.normalize_key(key) {
+            Ok(key) => self.branch(key),
+            Err(e) => return self.check_fail(&e),
+        };
+        self.check_signature(&key, msg)
+    }
+
+    /// Check the preimage of the given key
+    pub fn check_preimage(&mut self, key: String) -> bool {
+        let key = match self.normalize_key(&key) {
+            Ok(key) => key.to_string(),
+            Err(e) => return self.check_fail(&e),
+        };
+
+        // look up the hash and try to decode it
+        let hash = match self.stored_multihash(&key) {
+            Ok(hash) => hash,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        // make sure we have at least one parameter on the stack
+        if !self.require_pstack(1) {
+            return false;
+        }
+
+        // get the preimage data from the stack
+        let preimage = {
+            match self.pstack.top() {
+                Some(Value::Bin { hint: _, data }) => {
+                    match mh::Builder::new_from_bytes(hash.codec(), &data[..]) {
+                        Ok(builder) => match builder.try_build() {
+                            Ok(hash) => hash,
+                            Err(e) => return self.check_fail(&e.to_string()),
+                        },
+                        Err(e) => return self.check_fail(&e.to_string()),
+                    }
+                }
+                Some(Value::Str { hint: _, data }) => {
+                    match mh::Builder::new_from_bytes(hash.codec(), data.as_bytes()) {
+                        Ok(builder) => match builder.try_build() {
+                            Ok(hash) => hash,
+                            Err(e) => return self.check_fail(&e.to_string()),
+                        },
+                        Err(e) => return self.check_fail(&e.to_string()),
+                    }
+                }
+                Some(Value::Success(_) | Value::Failure(_)) => {
+                    return self.check_fail(UNEXPECTED_CONTROL_VALUE)
+                }
+                _ => return self.check_fail("no multihash data on stack"),
+            }
+        };
+
+        // check that the hashes match
+        if hash == preimage {
+            // the hash check passed so pop the argument from the stack
+            let _ = self.pstack.pop();
+            self.succeed()
+        } else {
+            // the hashes don't match
+            self.check_fail("preimage doesn't match")
+        }
+    }
+
+    /// Check a salted commitment, `hash(salt || preimage)`. The salt stored under `salt_key` is
+    /// prepended to the preimage on the stack before hashing it with the stored hash's algorithm.
+    /// The preimage is popped if the hashes match.
+    pub fn check_preimage_salted(&mut self, hash_key: &str, salt_key: &str) -> bool {
+        let hash_key = match self.normalize_key(hash_key) {
+            Ok(hash_key) => hash_key,
+            Err(e) => return self.check_fail(&e),
+        };
+        let salt_key = match self.normalize_key(salt_key) {
+            Ok(salt_key) => salt_key,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        let hash = match self.stored_multihash(hash_key) {
+            Ok(hash) => hash,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        let mut salted = match self.current.get(salt_key) {
+            Some(Value::Bin { hint: _, data }) => data.to_vec(),
+            Some(Value::Str { hint: _, data }) => data.into_bytes(),
+            Some(_) => {
+                return self
+                    .check_fail(&format!("unexpected value type associated with {salt_key}"))
+            }
+            None => return self.check_fail(&format!("kvp missing key: {salt_key}")),
+        };
+
+        // make sure we have at least one parameter on the stack
+        if !self.require_pstack(1) {
+            return false;
+        }
+
+        match self.pstack.top() {
+            Some(Value::Bin { hint: _, data }) => salted.extend_from_slice(&data),
+            Some(Value::Str { hint: _, data }) => salted.extend_from_slice(data.as_bytes()),
+            Some(Value::Success(_) | Value::Failure(_)) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
+            }
+            _ => return self.check_fail("no multihash data on stack"),
+        }
+
+        let preimage = match mh::Builder::new_from_bytes(hash.codec(), salted) {
+            Ok(builder) => match builder.try_build() {
+                Ok(hash) => hash,
+                Err(e) => return self.check_fail(&e.to_string()),
+            },
+            Err(e) => return self.check_fail(&e.to_string()),
+        };
+
+        if hash == preimage {
+            let _ = self.pstack.pop();
+            self.succeed()
+        } else {
+            warn!("check_preimage_salted({hash_key}, {salt_key}) -> false");
+            self.check_fail("preimage doesn't match")
+        }
+    }
+
+    /// Look up the [Multihash] stored under the key in the current store
+    fn stored_multihash(&self, key: &str) -> Result<Multihash, String> {
+        let bytes = match self.current.get(key) {
+            Some(Value::Bin { hint: _, data }) => data,
+            Some(Value::Str { hint: _, data }) if self.lenient_hex => {
+                hex::decode(data).map_err(|e| e.to_string())?.into()
+            }
+            Some(_) => return Err(format!("unexpected value type associated with {key}")),
+            None => return Err(format!("kvp missing key: {key}")),
+        };
+        Multihash::try_from(bytes.as_ref()).map_err(|e| e.to_string())
+    }
+
+    /// Check the preimage on the stack against the hashes associated with each of the keys,
+    /// succeeding on the first match
+    pub fn check_preimage_any(&mut self, keys: &[String]) -> bool {
+        // make sure we have at least one parameter on the stack
+        if !self.require_pstack(1) {
+            return false;
+        }
+
+        // get the preimage data from the stack
+        let data = match self.pstack.top() {
+            Some(Value::Bin { hint: _, data }) => data,
+            Some(Value::Str { hint: _, data }) => data.as_bytes().into(),
+            Some(Value::Success(_) | Value::Failure(_)) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
+            }
+            _ => return self.check_fail("no multihash data on stack"),
+        };
+
+        for key in keys {
+            let key = match self.normalize_key(key) {
+                Ok(key) => key,
+                Err(e) => return self.check_fail(&e),
+            };
+            let hash = match self.current.get(key) {
+                Some(Value::Bin { hint: _, data }) => match Multihash::try_from(data.as_ref()) {
+                    Ok(hash) => hash,
+                    Err(e) => {
+                        warn!("check_preimage_any: error decoding multihash {key}: {e}");
+                        continue;
+                    }
+                },
+                _ => {
+                    warn!("check_preimage_any: no multihash associated with {key}");
+                    continue;
+                }
+            };
+
+            let preimage = match mh::Builder::new_from_bytes(hash.codec(), &data[..]) {
+                Ok(builder) => match builder.try_build() {
+                    Ok(preimage) => preimage,
+                    Err(e) => return self.check_fail(&e.to_string()),
+                },
+                Err(e) => return self.check_fail(&e.to_string()),
+            };
+
+            if hash == preimage {
+                // the hash check passed so pop the argument from the stack
+                let _ = self.pstack.pop();
+                return self.succeed();
+            }
+        }
+
+        // none of the hashes match
+        self.check_fail("preimage doesn't match any hash")
+    }
+
+    /// Check the preimage on the stack against a raw digest (not a [Multihash]) associated with
+    /// the key. The preimage is hashed with the named algorithm, which is one of:
+    ///
+    /// - `sha2-256`
+    /// - `sha2-512`
+    pub fn check_preimage_raw(&mut self, key: &str, algo: &str) -> bool {
+        let key = match self.normalize_key(key) {
+            Ok(key) => key,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        // look up the raw digest
+        let digest = match self.current.get(key) {
+            Some(Value::Bin { hint: _, data }) => data,
+            Some(_) => {
+                return self.check_fail(&format!("unexpected value type associated with {key}"))
+            }
+            None => return self.check_fail(&format!("kvp missing key: {key}")),
+        };
+
+        // make sure we have at least one parameter on the stack
+        if !self.require_pstack(1) {
+            return false;
+        }
+
+        // get the preimage data from the stack
+        let preimage = match self.pstack.top() {
+            Some(Value::Bin { hint: _, data }) => data,
+            Some(Value::Str { hint: _, data }) => data.as_bytes().into(),
+            Some(Value::Success(_) | Value::Failure(_)) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
+            }
+            _ => return self.check_fail("no preimage data on stack"),
+        };
+
+        let Some(hash) = raw_digest(algo, &preimage) else {
+            return self.check_fail(&format!("unsupported hash algorithm: {algo}"));
+        };
+
+        // check that the digests match
+        if hash[..] == digest[..] {
+            // the hash check passed so pop the argument from the stack
+            let _ = self.pstack.pop();
+            self.succeed()
+        } else {
+            // the digests don't match
+            self.check_fail("preimage doesn't match")
+        }
+    }
+
+    /// Check the preimage on the stack against a truncated [Multihash] associated with the key,
+    /// such as a SHA-256 multihash keeping only the first 16 bytes of its digest. The preimage is
+    /// hashed with the stored multihash's codec, and only the first `len` bytes of its digest are
+    /// compared. The stored digest must be exactly `len` bytes long.
+    ///
+    /// Truncation weakens the commitment: a `len` byte digest resists preimages only up to
+    /// `8 * len` bits and collisions only up to `4 * len` bits, so keep `len` as long as the
+    /// storage allows.
+    pub fn check_preimage_prefix(&mut self, key: &str, len: usize) -> bool {
+        let key = match self.normalize_key(key) {
+            Ok(key) => key,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        // look up the truncated multihash
+        let hash = match self.stored_multihash(key) {
+            Ok(hash) => hash,
+            Err(e) => return self.check_fail(&e),
+        };
+        let codec = hash.codec();
+        let encoded: Vec<u8> = hash.into();
+        let digest = match multihash_digest(&encoded) {
+            Some(digest) if digest.len() == len => digest,
+            Some(digest) => {
+                return self.check_fail(&format!(
+                    "truncated digest for {key} is {} bytes, expected {len}",
+                    digest.len()
+                ))
+            }
+            None => return self.check_fail(&format!("malformed multihash associated with {key}")),
+        };
+
+        // make sure we have at least one parameter on the stack
+        if !self.require_pstack(1) {
+            return false;
+        }
+
+        // get the preimage data from the stack
+        let data = match self.pstack.top() {
+            Some(Value::Bin { hint: _, data }) => data,
+            Some(Value::Str { hint: _, data }) => data.as_bytes().into(),
+            Some(Value::Success(_) | Value::Failure(_)) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
+            }
+            _ => return self.check_fail("no preimage data on stack"),
+        };
+
+        let preimage: Vec<u8> = match mh::Builder::new_from_bytes(codec, &data[..]) {
+            Ok(builder) => match builder.try_build() {
+                Ok(preimage) => preimage.into(),
+                Err(e) => return self.check_fail(&e.to_string()),
+            },
+            Err(e) => return self.check_fail(&e.to_string()),
+        };
+        let preimage = match multihash_digest(&preimage) {
+            Some(preimage) if len > 0 && len <= preimage.len() => &preimage[..len],
+            _ => return self.check_fail(&format!("invalid digest prefix length: {len}")),
+        };
+
+        if preimage == digest {
+            let _ = self.pstack.pop();
+            self.succeed()
+        } else {
+            warn!("check_preimage_prefix({key}, {len}) -> false");
+            self.check_fail("preimage doesn't match")
+        }
+    }
+
+    /// Verifies the top of the stack matches the value associated with the key
+    pub fn check_eq(&mut self, key: &str) -> bool {
+        let key = match self.normalize_key(key) {
+            Ok(key) => key,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        // look up the value associated with the key
+        let value = {
+            match self.current.get(key) {
+                Some(Value::Bin { hint: _, data }) => data,
+                Some(Value::Str { hint: _, data }) => data.as_bytes().into(),
+                _ => {
+                    warn!("check_eq: no value associated with {key}");
+                    return self.check_fail(&format!("kvp missing key: {key}"));
+                }
+            }
+        };
+
+        // make sure we have at least one parameter on the stack
+        if !self.require_pstack(1) {
+            return false;
+        }
+
+        let stack_value = {
+            match self.pstack.top() {
+                Some(Value::Bin { hint: _, data }) => data,
+                Some(Value::Str { hint: _, data }) => data.as_bytes().into(),
+                Some(Value::Success(_) | Value::Failure(_)) => {
+                    return self.check_fail(UNEXPECTED_CONTROL_VALUE)
+                }
+                _ => {
+                    warn!("check_eq: no value on the stack");
+                    return self.check_fail("no value on the stack");
+                }
+            }
+        };
+
+        // check if equal
+        if value == stack_value {
+            // the values match so pop the argument from the stack
+            let _ = self.pstack.pop();
+            self.succeed()
+        } else {
+            // the values don't match
+            self.check_fail("values don't match")
+        }
+    }
+
+    /// Verifies the top of the stack matches the value associated with the key, ignoring case.
+    /// [Value::Str] values are compared ASCII case-insensitively, and [Value::Bin] values are
+    /// hex encoded first, so binary data matches its hex string in either case. Any other
+    /// value, such as a map or a timestamp, never matches.
+    pub fn check_eq_ci(&mut self, key: &str) -> bool {
+        let key = match self.normalize_key(key) {
+            Ok(key) => key,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        // look up the value associated with the key
+        let value = match self.current.get(key).as_ref().map(case_folded) {
+            Some(Some(value)) => value,
+            Some(None) => {
+                return self.check_fail(&format!("unexpected value type associated with {key}"))
+            }
+            None => {
+                warn!("check_eq_ci: no value associated with {key}");
+                return self.check_fail(&format!("kvp missing key: {key}"));
+            }
+        };
+
+        // make sure we have at least one parameter on the stack
+        if !self.require_pstack(1) {
+            return false;
+        }
+
+        let stack_value = match self.pstack.top() {
+            Some(Value::Success(_) | Value::Failure(_)) => {
+                return self.check_fail(UNEXPECTED_CONTROL_VALUE)
+            }
+            Some(top) => match case_folded(&top) {
+                Some(stack_value) => stack_value,
+                None => return self.check_fail("unexpected value type on the stack"),
+            },
+            None => return self.check_fail("no value on the stack"),
+        };
+
+        if value == stack_value {
+            // the values match so pop the argument from the stack
+            let _ = self.pstack.pop();
+            self.succeed()
+        } else {
+            self.check_fail("values don't match")
+        }
+    }
+
+    /// Verifies the clock has passed the [Value::Timestamp] associated with the key, such as a
+    /// not-before time. At exactly the timestamp, the check fails.
+    pub fn check_after(&mut self, key: &str) -> bool {
+        let key = match self.normalize_key(key) {
+            Ok(key) => key,
+            Err(e) => return self.check_fail(&e),
+        };
+        match self.stored_timestamp(key) {
+            Ok(timestamp) if self.clock.now() > timestamp => self.succeed(),
+            Ok(timestamp) => self.check_fail(&format!("{key} ({timestamp}) has not passed yet")),
+            Err(e) => self.check_fail(&e),
+        }
+    }
+
+    /// Verifies the clock hasn't reached the [Value::Timestamp] associated with the key, such
+    /// as an expiry time. At exactly the timestamp, the check fails.
+    pub fn check_before(&mut self, key: &str) -> bool {
+        let key = match self.normalize_key(key) {
+            Ok(key) => key,
+            Err(e) => return self.check_fail(&e),
+        };
+        match self.stored_timestamp(key) {
+            Ok(timestamp) if self.clock.now() < timestamp => self.succeed(),
+            Ok(timestamp) => self.check_fail(&format!("{key} ({timestamp}) has passed")),
+            Err(e) => self.check_fail(&e),
+        }
+    }
+
+    /// Look up the [Value::Timestamp] stored under the key in the current store
+    fn stored_timestamp(&self, key: &str) -> Result<u64, String> {
+        let key = self.normalize_key(key)?;
+        match self.current.get(key) {
+            Some(Value::Timestamp(timestamp)) => Ok(timestamp),
+            Some(_) => Err(format!("unexpected value type associated with {key}")),
+            None => Err(format!("kvp missing key: {key}")),
+        }
+    }
+
+    /// Verifies the values associated with two keys are equal, without touching the stack
+    pub fn check_keys_eq(&mut self, key_a: &str, key_b: &str) -> bool {
+        let key_a = match self.normalize_key(key_a) {
+            Ok(key_a) => key_a,
+            Err(e) => return self.check_fail(&e),
+        };
+        let key_b = match self.normalize_key(key_b) {
+            Ok(key_b) => key_b,
+            Err(e) => return self.check_fail(&e),
+        };
+
+        let Some(a) = self.current.get(key_a) else {
+            warn!("check_keys_eq: no value associated with {key_a}");
+            return self.check_fail(&format!("kvp missing key: {key_a}"));
+        };
+        let Some(b) = self.current.get(key_b) else {
+            warn!("check_keys_eq: no value associated with {key_b}");
+            return self.check_fail(&format!("kvp missing key: {key_b}"));
+        };
+
+        // compare the data only, ignoring any hints
+        if a.content_eq(&b) {
+            self.succeed()
+        } else {
+            self.check_fail("values don't match")
+        }
+    }
+
+    /// Normalize a key given by a script, when key normalization is enabled: surrounding
+    /// whitespace is trimmed, and keys that are empty once trimmed are rejected. Keys are used
+    /// as given otherwise.
+    pub fn normalize_key<'a>(&self, key: &'a str) -> Result<&'a str, String> {
+        if !self.normalize_keys {
+            return Ok(key);
+        }
+        let trimmed = key.trim();
+        if trimmed.is_empty() {
+            warn!("normalize_key: rejecting empty key {key:?}");
+            return Err(format!("empty key: {key:?}"));
+        }
+        Ok(trimmed)
+    }
+
+    /// Make sure there are at least `n` parameters on the stack. If not, this is a failed check
+    /// with a uniform error message, and false is returned.
+    pub fn require_pstack(&mut self, n: usize) -> bool {
+        let len = self.pstack.len();
+        if len >= n {
+            return true;
+        }
+        warn!("not enough parameters on the stack: {len} (need {n})");
+        self.check_fail(&format!(
+            "not enough parameters on the stack: {len} (need {n})"
+        ))
+    }
+
+    /// Run a check, restoring the stacks and check counters if it panics and recording a
+    /// failure instead, so a panic can't leave a popped-but-not-completed operation behind.
+    /// The call is traced like [Context::traced].
+    ///
+    /// Rather than copying the stacks, only the length of the return stack and the top
+    /// `MAX_CHECK_POPS` parameters are kept, which is enough as long as a check pops at most
+    /// that many parameters. Both stacks are truncated back to where they were, so anything the
+    /// check pushed onto either of them, such as the hash `check_signature_chain` pushes onto
+    /// the parameter stack, is dropped, and the popped parameters are put back.
+    pub fn guarded(
+        &mut self,
+        name: &str,
+        args: &[&str],
+        check: impl FnOnce(&mut Self) -> bool,
+    ) -> bool {
+        self.traced(name, args, |context| {
+            let below = context.pstack.len().saturating_sub(MAX_CHECK_POPS);
+            let popped = context.pstack.stack[below..].to_vec();
+            let rstack_len = context.rstack.len();
+            let (check_count, checks_run) = (context.check_count, context.checks_run);
+
+            match std::panic::catch_unwind(AssertUnwindSafe(|| check(context))) {
+                Ok(result) => result,
+                Err(_) => {
+                    warn!("{name} panicked, restoring the stacks");
+                    context.pstack.stack.truncate(below);
+                    context.pstack.stack.extend(popped);
+                    context.rstack.stack.truncate(rstack_len);
+                    context.check_count = check_count;
+                    context.checks_run = checks_run;
+                    context.check_fail(&format!("{name} panicked"))
+                }
+            }
+        })
+    }
+
+    /// Run a function called by a script. When call tracing is enabled, emits a trace event
+    /// with the function name, its arguments and its result.
+    pub fn traced<T: std::fmt::Debug>(
+        &mut self,
+        name: &str,
+        args: &[&str],
+        f: impl FnOnce(&mut Self) -> T,
+    ) -> T {
+        let result = f(self);
+        if self.call_tracing {
+            trace!("{name}({}) -> {result:?}", args.join(", "));
+        }
+        result
+    }
+
+    /// Increment the check counter and to push a FAILURE marker on the return stack
+    pub fn check_fail(&mut self, err: &str) -> bool {
+        // update the context check_count, saturating rather than wrapping on 32-bit targets
+        self.check_count = self.check_count.saturating_add(1);
+        self.checks_run = self.checks_run.saturating_add(1);
+        // fail
+        self.fail(err)
+    }
+
+    /// Increment the check counter and to push a FAILURE marker on the return stack
+    pub fn fail(&mut self, err: &str) -> bool {
+        // push the FAILURE onto the return stack
+        self.rstack.push(Value::Failure(err.to_string()));
+        false
+    }
+
+    /// Push a SUCCESS marker onto the return stack
+    pub fn succeed(&mut self) -> bool {
+        self.checks_run = self.checks_run.saturating_add(1);
+        // push the SUCCESS marker with the check count
+        self.rstack.push(self.check_count.into());
+        // return that we succeeded
+        true
+    }
+
+    /// Push the value associated with the key onto the parameter stack
+    pub fn push(&mut self, key: &str) -> bool {
+        let key = match self.normalize_key(key) {
+            Ok(key) => key,
+            Err(e) => return self.fail(&e),
+        };
+
+        // in combined mode current is the real current store, so proofs come from proposed
+        let value = if self.combined {
+            self.proposed.get(key)
+        } else {
+            self.current.get(key)
+        };
+
+        // try to look up the key-value pair by key and push the result onto the stack
+        match value {
+            Some(v) => {
+                if let Some(max) = self.max_value_size {
+                    let size = v.len();
+                    if size > max {
+                        warn!("push: value for {key} exceeds the maximum size ({size} > {max})");
+                        return self.fail(&format!(
+                            "value for {key} exceeds the maximum size ({size} > {max} bytes)"
+                        ));
+                    }
+                }
+                if self.warn_duplicate_pushes {
+                    if self.pushed.iter().any(|k| k == key) {
+                        warn!("push: {key} pushed more than once");
+                        self.warnings.push(format!("{key} pushed more than once"));
+                    }
+                    self.pushed.push(key.to_string());
+                }
+                self.pstack.push(v.clone());
+                true
+            }
+            None => {
+                warn!("push: no value associated with {key}");
+                self.fail(&format!("kvp missing key: {key}"))
+            }
+        }
+    }
+
+    /// Look up the value for the key from either store, so a script doesn't need to know which
+    /// store holds it. The proposed store is checked first, then the current store.
+    pub fn lookup(&self, key: &str) -> Option<Value> {
+        let key = self.normalize_key(key).ok()?;
+        self.proposed.get(key).or_else(|| self.current.get(key))
+    }
+
+    /// Read a field of the [Value::Map] associated with the key, looked up like
+    /// [Context::lookup]. Returns `None` if the key is missing, isn't a map, or has no such field.
+    pub fn get_field(&self, key: &str, field: &str) -> Option<Value> {
+        match self.lookup(key) {
+            Some(Value::Map(mut fields)) => fields.remove(field),
+            _ => None,
+        }
+    }
+
+    /// The byte length of the value associated with the key, or zero if the key is missing
+    pub fn value_len(&self, key: &str) -> usize {
+        let Ok(key) = self.normalize_key(key) else {
+            return 0;
+        };
+        self.current.get(key).map_or(0, |v| v.len())
+    }
+
+    /// Push the value of `then_key` if `test` is true, or of `else_key` otherwise, so a script
+    /// can pick which proof to check based on the result of a prior check
+    pub fn cond(&mut self, test: bool, then_key: &str, else_key: &str) -> bool {
+        let key = if test { then_key } else { else_key };
+        debug!("cond({test}, {then_key}, {else_key}) -> {key}");
+        self.push(key)
+    }
+
+    /// Record a labeled result for the script's caller, without touching the stacks. Lets
+    /// scripts emit structured diagnostics, such as the result of each of several checks.
+    pub fn report(&mut self, label: &str, value: Value) {
+        self.reports.push((label.to_string(), value));
+    }
+
+    /// Push the value for the given key onto the parameter stack like [Context::push], also
+    /// returning the pushed value. Returns `None` if the push failed.
+    pub fn push_get(&mut self, key: &str) -> Option<Value> {
+        if self.push(key) {
+            self.pstack.top()
+        } else {
+            None
+        }
+    }
+
+    /// Push a domain segment, so subsequent branch() keys are nested under it.
+    /// Fails if the maximum domain depth has been reached.
+    pub fn push_domain(&mut self, segment: &str) -> bool {
+        if self.domains.len() >= self.max_domain_depth {
+            warn!(
+                "push_domain: maximum domain depth ({}) reached",
+                self.max_domain_depth
+            );
+            return self.fail(&format!(
+                "maximum domain depth ({}) reached",
+                self.max_domain_depth
+            ));
+        }
+
+        let base = self.domain.trim_end_matches('/');
+        let domain = format!("{base}/{}/", segment.trim_matches('/'));
+        debug!("push_domain({segment}) -> {domain}");
+        self.domains
+            .push(std::mem::replace(&mut self.domain, domain));
+        true
+    }
+
+    /// Pop the last pushed domain segment, restoring the previous domain
+    pub fn pop_domain(&mut self) -> bool {
+        match self.domains.pop() {
+            Some(domain) => {
+                self.domain = domain;
+                true
+            }
+            None => {
+                warn!("pop_domain: no domain to pop");
+                self.fail("no domain to pop")
+            }
+        }
+    }
+
+    /// Clear all pushed domains and reset the domain back to "/"
+    pub fn reset_domain(&mut self) {
+        self.domains.clear();
+        self.domain = "/".to_string();
+    }
+
+    /// Calculate the full key given the context
+    pub fn branch(&self, key: &str) -> String {
+        let s = format!("{}{}", self.domain, key);
+        debug!("branch({}) -> {}", key, s.as_str());
         s
     }
 }
+
+/// The lowercase text `check_eq_ci` compares a value by: the lowercased string of a
+/// [Value::Str], or the hex encoding of a [Value::Bin]. Other values have none.
+fn case_folded(value: &Value) -> Option<String> {
+    match value {
+        Value::Str { hint: _, data } => Some(data.to_ascii_lowercase()),
+        Value::Bin { hint: _, data } => Some(hex::encode(data)),
+        _ => None,
+    }
+}
+
+/// Hashes the data with the named algorithm, returning the raw digest, or `None` if the
+/// algorithm isn't supported
+fn raw_digest(algo: &str, data: &[u8]) -> Option<Vec<u8>> {
+    match algo {
+        "sha2-256" => Some(Sha256::digest(data).to_vec()),
+        "sha2-512" => Some(Sha512::digest(data).to_vec()),
+        _ => None,
+    }
+}
+
+/// The digest of an encoded [Multihash], which follows its varint codec and varint digest length
+fn multihash_digest(encoded: &[u8]) -> Option<&[u8]> {
+    let (_, rest) = split_varint(encoded)?;
+    let (len, digest) = split_varint(rest)?;
+    (u64::try_from(digest.len()).ok()? == len).then_some(digest)
+}
+
+/// Splits an unsigned varint off the front of the bytes, returning its value and the rest
+fn split_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::stack::Stack as _;
+
+    /// A valid ed25519 public [Multikey]
+    const PUBKEY: &str = "ba24ed010874657374206b657901012069c9e8cd599542b5ff7e4cdc4265847feb9785330557edd6a9edae741ed4c3b2";
+
+    fn context() -> Context<ContextPairs, ContextPairs> {
+        Context::new(
+            Either::Prop(ContextPairs::default()),
+            ContextPairs::default(),
+        )
+    }
+
+    #[test]
+    fn test_push_domain_depth_limit() {
+        let mut context = context();
+        context.max_domain_depth = 2;
+
+        assert!(context.push_domain("forks"));
+        assert!(context.push_domain("child"));
+        assert_eq!(context.branch("pubkey"), "/forks/child/pubkey");
+
+        // exceeding the depth fails gracefully and leaves the domain untouched
+        assert!(!context.push_domain("grandchild"));
+        assert_eq!(context.domain, "/forks/child/");
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure(
+                "maximum domain depth (2) reached".to_string()
+            ))
+        );
+
+        assert!(context.pop_domain());
+        assert_eq!(context.domain, "/forks/");
+    }
+
+    #[test]
+    fn test_reset_domain() {
+        let mut context = context();
+
+        assert!(context.push_domain("forks"));
+        assert!(context.push_domain("child"));
+
+        context.reset_domain();
+        assert_eq!(context.domain, "/");
+        assert!(!context.pop_domain());
+    }
+
+    #[test]
+    fn test_context_pairs_iter() {
+        let mut pairs = ContextPairs::default();
+        pairs.put("/entry/", &"for great justice".into());
+        pairs.put("/entry/proof", &vec![1u8, 2, 3].into());
+        pairs.put("/pubkey", &vec![4u8, 5, 6].into());
+
+        let expected = HashMap::from([
+            ("/entry/".to_string(), Value::from("for great justice")),
+            ("/entry/proof".to_string(), Value::from(vec![1u8, 2, 3])),
+            ("/pubkey".to_string(), Value::from(vec![4u8, 5, 6])),
+        ]);
+
+        let mut collected = HashMap::new();
+        for (key, value) in &pairs {
+            collected.insert(key.clone(), value.clone());
+        }
+        assert_eq!(collected, expected);
+
+        let iterated: HashMap<String, Value> =
+            pairs.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        assert_eq!(iterated, expected);
+    }
+
+    #[test]
+    fn test_push_max_value_size() {
+        let mut context = context();
+        context.max_value_size = Some(4);
+        Arc::make_mut(&mut context.current).put("/small", &vec![0u8; 4].into());
+        Arc::make_mut(&mut context.current).put("/huge", &vec![0u8; 1024].into());
+
+        assert!(context.push("/small"));
+        assert!(!context.push("/huge"));
+        assert_eq!(context.pstack.len(), 1);
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure(
+                "value for /huge exceeds the maximum size (1024 > 4 bytes)".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_push_max_value_size_map() {
+        let mut context = context();
+        context.max_value_size = Some(4);
+        let map = Value::Map(HashMap::from([("sig".to_string(), vec![0u8; 1024].into())]));
+        Arc::make_mut(&mut context.current).put("/map", &map);
+
+        assert!(!context.push("/map"));
+        assert_eq!(context.pstack.len(), 0);
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure(
+                "value for /map exceeds the maximum size (1027 > 4 bytes)".to_string()
+            ))
+        );
+    }
+
+    fn sha2_256(data: &[u8]) -> Vec<u8> {
+        mh::Builder::new_from_bytes(multicodec::Codec::Sha2256, data)
+            .unwrap()
+            .try_build()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn test_check_preimage_any() {
+        let mut context = context();
+        Arc::make_mut(&mut context.current).put("/hash-current", &sha2_256(b"old secret").into());
+        Arc::make_mut(&mut context.current).put("/hash-next", &sha2_256(b"new secret").into());
+
+        let keys = ["/hash-current".to_string(), "/hash-next".to_string()];
+
+        context.pstack.push(b"wrong secret".to_vec().into());
+        assert!(!context.check_preimage_any(&keys));
+        assert_eq!(context.pstack.len(), 1);
+
+        context.pstack.push(b"new secret".to_vec().into());
+        assert!(context.check_preimage_any(&keys));
+        assert_eq!(context.pstack.len(), 1);
+        assert_eq!(context.rstack.top(), Some(Value::Success(1)));
+    }
+
+    #[test]
+    fn test_check_preimage_raw() {
+        let mut context = context();
+        Arc::make_mut(&mut context.current)
+            .put("/digest", &Sha256::digest(b"secret").to_vec().into());
+
+        context.pstack.push(b"wrong secret".to_vec().into());
+        assert!(!context.check_preimage_raw("/digest", "sha2-256"));
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure("preimage doesn't match".to_string()))
+        );
+        let _ = context.pstack.pop();
+
+        context.pstack.push(b"secret".to_vec().into());
+        assert!(!context.check_preimage_raw("/digest", "sha2-512"));
+        assert!(!context.check_preimage_raw("/digest", "md5"));
+        assert!(context.check_preimage_raw("/digest", "sha2-256"));
+        assert!(context.pstack.is_empty());
+    }
+
+    #[test]
+    fn test_check_after_and_before() {
+        let mut context = context();
+        context.clock = Clock(Arc::new(|| 1_000));
+        Arc::make_mut(&mut context.current).put("/not-before", &Value::Timestamp(999));
+        Arc::make_mut(&mut context.current).put("/expires", &Value::Timestamp(1_001));
+        Arc::make_mut(&mut context.current).put("/now", &Value::Timestamp(1_000));
+        Arc::make_mut(&mut context.current).put("/ttl", &Value::Duration(1_001));
+
+        assert!(context.check_after("/not-before"));
+        assert!(!context.check_before("/not-before"));
+        assert!(context.check_before("/expires"));
+        assert!(!context.check_after("/expires"));
+
+        // both boundaries are exclusive
+        assert!(!context.check_after("/now"));
+        assert!(!context.check_before("/now"));
+
+        assert!(!context.check_before("/ttl"));
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure(
+                "unexpected value type associated with /ttl".to_string()
+            ))
+        );
+        assert!(!context.check_after("/missing"));
+
+        // error messages name the normalized key
+        context.normalize_keys = true;
+        assert!(!context.check_after(" /expires "));
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure(
+                "/expires (1001) has not passed yet".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_check_signature_branch_normalized() {
+        let mut context = context();
+        context.normalize_keys = true;
+        context.domain = "/branch".to_string();
+
+        // the padded key is trimmed before it is joined to the domain
+        assert!(!context.check_signature_branch(" /pubkey ", "/entry/"));
+        let Some(Value::Failure(reason)) = context.rstack.top() else {
+            panic!("expected a failure");
+        };
+        assert!(reason.contains("/branch/pubkey"), "{reason}");
+        assert!(!reason.contains("/branch /pubkey"), "{reason}");
+    }
+
+    #[test]
+    fn test_check_preimage_prefix() {
+        // a sha2-256 multihash, codec 0x12, keeping 16 of its 32 digest bytes
+        let mut truncated = vec![0x12, 16];
+        truncated.extend_from_slice(&Sha256::digest(b"secret")[..16]);
+
+        let mut context = context();
+        Arc::make_mut(&mut context.current).put("/digest", &truncated.into());
+
+        context.pstack.push(b"wrong secret".to_vec().into());
+        assert!(!context.check_preimage_prefix("/digest", 16));
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure("preimage doesn't match".to_string()))
+        );
+        let _ = context.pstack.pop();
+
+        context.pstack.push(b"secret".to_vec().into());
+        assert!(!context.check_preimage_prefix("/digest", 20));
+        assert!(context.check_preimage_prefix("/digest", 16));
+        assert!(context.pstack.is_empty());
+    }
+
+    #[test]
+    fn test_empty_pstack_uniform_error() {
+        let pubkey = hex::decode(PUBKEY).unwrap();
+
+        let mut context = context();
+        Arc::make_mut(&mut context.current).put("/pubkey", &pubkey.into());
+        Arc::make_mut(&mut context.current).put("/hash", &sha2_256(b"secret").into());
+        Arc::make_mut(&mut context.current)
+            .put("/digest", &Sha256::digest(b"secret").to_vec().into());
+        Arc::make_mut(&mut context.current).put("/value", &"zig".into());
+        Arc::make_mut(&mut context.proposed).put("/entry/", &"for great justice".into());
+
+        let expected = Some(Value::Failure(
+            "not enough parameters on the stack: 0 (need 1)".to_string(),
+        ));
+
+        assert!(!context.check_signature("/pubkey", "/entry/"));
+        assert_eq!(context.rstack.top(), expected);
+
+        assert!(!context.check_preimage("/hash".to_string()));
+        assert_eq!(context.rstack.top(), expected);
+
+        assert!(!context.check_preimage_any(&["/hash".to_string()]));
+        assert_eq!(context.rstack.top(), expected);
+
+        assert!(!context.check_preimage_raw("/digest", "sha2-256"));
+        assert_eq!(context.rstack.top(), expected);
+
+        assert!(!context.check_eq("/value"));
+        assert_eq!(context.rstack.top(), expected);
+    }
+
+    #[test]
+    fn test_compare_and_put() {
+        let mut pairs = ContextPairs::default();
+        let old_root = Value::from("old-root");
+        let new_root = Value::from("new-root");
+
+        // the key must be absent when nothing is expected
+        assert!(pairs.compare_and_put("/root", None, &old_root));
+        assert!(!pairs.compare_and_put("/root", None, &new_root));
+        assert_eq!(pairs.get("/root"), Some(old_root.clone()));
+
+        // mismatched expectations leave the value untouched
+        assert!(!pairs.compare_and_put("/root", Some(&new_root), &new_root));
+        assert_eq!(pairs.get("/root"), Some(old_root.clone()));
+
+        assert!(pairs.compare_and_put("/root", Some(&old_root), &new_root));
+        assert_eq!(pairs.get("/root"), Some(new_root));
+    }
+
+    #[test]
+    fn test_control_value_on_pstack() {
+        let pubkey = hex::decode(PUBKEY).unwrap();
+
+        let mut context = context();
+        Arc::make_mut(&mut context.current).put("/pubkey", &pubkey.into());
+        Arc::make_mut(&mut context.current).put("/value", &"zig".into());
+        Arc::make_mut(&mut context.proposed).put("/entry/", &"for great justice".into());
+
+        let expected = Some(Value::Failure(UNEXPECTED_CONTROL_VALUE.to_string()));
+
+        context.pstack.push(Value::Success(0));
+        assert!(!context.check_signature("/pubkey", "/entry/"));
+        assert_eq!(context.rstack.top(), expected);
+
+        context.pstack.push(Value::Failure("captured".to_string()));
+        assert!(!context.check_eq("/value"));
+        assert_eq!(context.rstack.top(), expected);
+    }
+
+    #[test]
+    fn test_check_eq_ci() {
+        let mut context = context();
+        Arc::make_mut(&mut context.current).put("/upper", &"ABCD".into());
+        Arc::make_mut(&mut context.current).put("/bin", &vec![0xabu8, 0xcd].into());
+        Arc::make_mut(&mut context.current).put("/time", &Value::Timestamp(0));
+
+        context.pstack.push("abcd".into());
+        assert!(!context.check_eq("/upper"));
+        assert!(context.check_eq_ci("/upper"));
+        assert!(context.pstack.is_empty());
+
+        // binary data matches its hex string, whatever the case
+        context.pstack.push("ABcd".into());
+        assert!(context.check_eq_ci("/bin"));
+
+        context.pstack.push("abcd".into());
+        assert!(!context.check_eq_ci("/time"));
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure(
+                "unexpected value type associated with /time".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_check_keys_eq() {
+        let mut context = context();
+        let current = Arc::make_mut(&mut context.current);
+        current.put("/old-root", &vec![1u8, 2, 3].into());
+        current.put("/new-root", &vec![1u8, 2, 3].into());
+        current.put("/other-root", &vec![4u8, 5, 6].into());
+
+        assert!(context.check_keys_eq("/old-root", "/new-root"));
+        assert!(!context.check_keys_eq("/old-root", "/other-root"));
+        assert!(!context.check_keys_eq("/old-root", "/missing-root"));
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure("kvp missing key: /missing-root".to_string()))
+        );
+        assert!(context.pstack.is_empty());
+    }
+
+    #[test]
+    fn test_context_pairs_from_iter() {
+        let pairs: ContextPairs = ["/entry/", "/entry/proof", "/pubkey"]
+            .into_iter()
+            .map(|key| (key.to_string(), Value::from(format!("{key}-value"))))
+            .collect();
+
+        assert_eq!(pairs.len(), 3);
+        assert_eq!(pairs.get("/entry/"), Some(Value::from("/entry/-value")));
+        assert_eq!(
+            pairs.get("/entry/proof"),
+            Some(Value::from("/entry/proof-value"))
+        );
+        assert_eq!(pairs.get("/pubkey"), Some(Value::from("/pubkey-value")));
+    }
+
+    #[test]
+    fn test_push_get() {
+        let mut context = context();
+        let value = Value::Bin {
+            hint: "proof".to_string(),
+            data: vec![1, 2, 3].into(),
+        };
+        Arc::make_mut(&mut context.current).put("/entry/proof", &value);
+
+        assert_eq!(context.push_get("/entry/proof"), Some(value.clone()));
+        assert_eq!(context.pstack.top(), Some(value));
+
+        assert_eq!(context.push_get("/missing"), None);
+        assert_eq!(context.pstack.len(), 1);
+    }
+
+    #[test]
+    fn test_guarded_panic_restores_stacks() {
+        let mut context = context();
+        for data in ["entry", "sig", "pubkey"] {
+            context.pstack.push(data.into());
+        }
+        let pstack = context.pstack.clone();
+
+        let result = context.guarded("check_panics", &[], |context| {
+            // pop both parameters and push a result, then panic before completing the check
+            context.pstack.pop();
+            context.pstack.pop();
+            context.pstack.push("hash".into());
+            context.rstack.push(Value::Success(0));
+            panic!("check blew up");
+        });
+
+        assert!(!result);
+        assert_eq!(context.pstack, pstack);
+        assert_eq!(context.rstack.len(), 1);
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure("check_panics panicked".to_string()))
+        );
+        assert_eq!(context.check_count, 1);
+    }
+
+    #[test]
+    fn test_value_len() {
+        let mut context = context();
+        Arc::make_mut(&mut context.current).put("/entry/proof", &vec![1u8, 2, 3, 4].into());
+        Arc::make_mut(&mut context.current).put("/entry/empty", &Vec::<u8>::new().into());
+
+        assert_eq!(context.value_len("/entry/proof"), 4);
+        assert_eq!(context.value_len("/entry/empty"), 0);
+        assert_eq!(context.value_len("/missing"), 0);
+    }
+
+    #[test]
+    fn test_check_signature_recover() {
+        use k256::ecdsa::SigningKey;
+
+        // the well known address of the secp256k1 private key 0x01
+        const ADDRESS: &str = "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf";
+
+        let mut secret = [0u8; 32];
+        secret[31] = 1;
+        let signing_key = SigningKey::from_slice(&secret).unwrap();
+
+        let message = b"for great justice, move every zig";
+        let (signature, recovery_id) = signing_key
+            .sign_prehash_recoverable(&Keccak256::digest(message))
+            .unwrap();
+        let mut sig = signature.to_bytes().to_vec();
+        sig.push(recovery_id.to_byte() + 27);
+
+        let mut context = context();
+        context.current = Arc::new(Either::Curr(ContextPairs::default()));
+        Arc::make_mut(&mut context.current).put("/address", &ADDRESS.into());
+        Arc::make_mut(&mut context.proposed).put("/entry/", &message.to_vec().into());
+
+        // a signature over a different message recovers to some other address
+        context.pstack.push(sig.clone().into());
+        Arc::make_mut(&mut context.proposed).put("/other", &b"zag".to_vec().into());
+        assert!(!context.check_signature_recover("/other", "/address"));
+        assert_eq!(context.pstack.len(), 1);
+
+        // a non-standard v byte for the same recovery id is rejected
+        let mut nonstandard = sig.clone();
+        nonstandard[64] = recovery_id.to_byte() + 54;
+        context.pstack.push(nonstandard.into());
+        assert!(!context.check_signature_recover("/entry/", "/address"));
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure("invalid recoverable signature".to_string()))
+        );
+        context.pstack.pop();
+
+        // the high-S twin with the flipped recovery id recovers the same signer, but is rejected
+        let (r, s) = signature.split_scalars();
+        let high_s = Signature::from_scalars(r, -s).unwrap();
+        let mut malleated = high_s.to_bytes().to_vec();
+        malleated.push((recovery_id.to_byte() ^ 1) + 27);
+        context.pstack.push(malleated.into());
+        assert!(!context.check_signature_recover("/entry/", "/address"));
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure("non-canonical high-S signature".to_string()))
+        );
+        context.pstack.pop();
+
+        assert!(context.check_signature_recover("/entry/", "/address"));
+        assert_eq!(context.pstack.len(), 0);
+
+        // v may also be the bare recovery id
+        sig[64] = recovery_id.to_byte();
+        context.pstack.push(sig.into());
+        assert!(context.check_signature_recover("/entry/", "/address"));
+    }
+
+    #[test]
+    fn test_duplicate_push_warning() {
+        let mut context = context();
+        Arc::make_mut(&mut context.current).put("/proof", &"zig".into());
+        Arc::make_mut(&mut context.current).put("/entry/", &"zag".into());
+
+        // without the flag, duplicates are silent
+        assert!(context.push("/proof"));
+        assert!(context.push("/proof"));
+        assert!(context.warnings.is_empty());
+
+        context.warn_duplicate_pushes = true;
+        assert!(context.push("/proof"));
+        assert!(context.push("/entry/"));
+        assert!(context.warnings.is_empty());
+
+        assert!(context.push("/proof"));
+        assert_eq!(context.warnings, vec!["/proof pushed more than once"]);
+    }
+
+    #[test]
+    fn test_check_preimage_salted() {
+        let mut context = context();
+        let salt = b"pepper".to_vec();
+        let commitment = sha2_256(&[salt.as_slice(), b"zig"].concat());
+        Arc::make_mut(&mut context.current).put("/hash", &commitment.into());
+        Arc::make_mut(&mut context.current).put("/salt", &salt.into());
+        Arc::make_mut(&mut context.current).put("/wrong_salt", &b"paprika".to_vec().into());
+
+        context.pstack.push(b"zig".to_vec().into());
+
+        assert!(!context.check_preimage_salted("/hash", "/wrong_salt"));
+        assert_eq!(context.pstack.len(), 1);
+
+        assert!(context.check_preimage_salted("/hash", "/salt"));
+        assert!(context.pstack.is_empty());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut context = context();
+        context.current = Arc::new(Either::Curr(ContextPairs::default()));
+        Arc::make_mut(&mut context.current).put("/pubkey", &"current".into());
+        Arc::make_mut(&mut context.current).put("/both", &"current".into());
+        Arc::make_mut(&mut context.proposed).put("/entry/", &"proposed".into());
+        Arc::make_mut(&mut context.proposed).put("/both", &"proposed".into());
+
+        assert_eq!(context.lookup("/pubkey"), Some("current".into()));
+        assert_eq!(context.lookup("/entry/"), Some("proposed".into()));
+        assert_eq!(context.lookup("/both"), Some("proposed".into()));
+        assert_eq!(context.lookup("/missing"), None);
+    }
+
+    #[test]
+    fn test_key_normalization() {
+        let mut context = context();
+        Arc::make_mut(&mut context.current).put("/entry/", &"zig".into());
+
+        // strict mode uses keys as given
+        assert!(!context.push("/entry/ "));
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure("kvp missing key: /entry/ ".to_string()))
+        );
+
+        context.normalize_keys = true;
+        assert!(context.push("/entry/ "));
+        assert_eq!(context.pstack.top(), Some("zig".into()));
+        assert_eq!(context.lookup("  /entry/"), Some("zig".into()));
+
+        assert!(!context.push("   "));
+        assert_eq!(
+            context.rstack.top(),
+            Some(Value::Failure("empty key: \"   \"".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_context_pairs_eq_and_hash() {
+        use std::collections::hash_map::DefaultHasher;
+
+        fn hash(pairs: &ContextPairs) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            pairs.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let mut a = ContextPairs::default();
+        let mut b = ContextPairs::default();
+        for i in 0..16 {
+            a.put(&format!("/key/{i}"), &Value::Success(i));
+        }
+        for i in (0..16).rev() {
+            b.put(&format!("/key/{i}"), &Value::Success(i));
+        }
+
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+
+        b.put("/key/0", &"zig".into());
+        assert_ne!(a, b);
+        assert_ne!(hash(&a), hash(&b));
+    }
+
+    #[test]
+    fn test_check_count_saturates() {
+        let mut context = context();
+        // start just below the cap instead of running billions of checks
+        context.check_count = usize::MAX - 1;
+        context.checks_run = usize::MAX - 1;
+
+        context.check_fail("one");
+        context.check_fail("two");
+        assert_eq!(context.check_count, usize::MAX);
+        assert_eq!(context.checks_run, usize::MAX);
+
+        context.succeed();
+        assert_eq!(context.checks_run, usize::MAX);
+        assert_eq!(context.rstack.top(), Some(Value::Success(usize::MAX)));
+    }
+}
@@ -0,0 +1,130 @@
+//! Dump module
+use crate::{Pairs, Value};
+use std::fmt;
+
+/// A snapshot of a [crate::Comrade] context, for attaching the state of a failed
+/// verification to a bug report. Values are described by type and size, and the data of
+/// [Value::Bin] and [Value::Str] values is left out when redaction is on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContextDump {
+    /// The keys in the current store with their described values, sorted by key, or `None` if
+    /// the store can't list its keys
+    pub current: Option<Vec<(String, String)>>,
+    /// The keys in the proposed store with their described values, sorted by key, or `None` if
+    /// the store can't list its keys
+    pub proposed: Option<Vec<(String, String)>>,
+    /// The described values on the parameter stack, from bottom to top
+    pub pstack: Vec<String>,
+    /// The described values on the return stack, from bottom to top
+    pub rstack: Vec<String>,
+    /// The current key domain
+    pub domain: String,
+    /// The number of failed checks
+    pub check_count: usize,
+}
+
+impl ContextDump {
+    /// Describes every key-value pair in the store, sorted by key, or returns `None` if the
+    /// store doesn't implement [Pairs::keys]
+    pub(crate) fn pairs(store: &impl Pairs, redact: bool) -> Option<Vec<(String, String)>> {
+        let mut keys = store.keys()?;
+        keys.sort();
+        let pairs = keys
+            .into_iter()
+            .filter_map(|key| {
+                let value = store.get(&key)?;
+                Some((key, describe(&value, redact)))
+            })
+            .collect();
+        Some(pairs)
+    }
+
+    /// Describes every value on a stack, from bottom to top
+    pub(crate) fn values<'a>(values: impl Iterator<Item = &'a Value>, redact: bool) -> Vec<String> {
+        values.map(|value| describe(value, redact)).collect()
+    }
+}
+
+/// Describes a value by its type and size. Binary data is hex encoded and strings are quoted, or
+/// both are left out when `redact` is set.
+fn describe(value: &Value, redact: bool) -> String {
+    match value {
+        Value::Bin { data, .. } if redact => format!("bin({} bytes) <redacted>", data.len()),
+        Value::Bin { data, .. } => format!("bin({} bytes) {}", data.len(), hex::encode(data)),
+        Value::Str { data, .. } if redact => format!("str({} bytes) <redacted>", data.len()),
+        Value::Str { data, .. } => format!("str({} bytes) {data:?}", data.len()),
+        Value::Map(fields) => {
+            let mut fields = fields
+                .iter()
+                .map(|(name, value)| format!("{name}: {}", describe(value, redact)))
+                .collect::<Vec<_>>();
+            fields.sort();
+            format!("map({} fields) {{{}}}", fields.len(), fields.join(", "))
+        }
+        Value::Timestamp(secs) => format!("timestamp {secs}"),
+        Value::Duration(secs) => format!("duration {secs}s"),
+        Value::Success(count) => format!("success {count}"),
+        Value::Failure(reason) => format!("failure {reason:?}"),
+    }
+}
+
+impl fmt::Display for ContextDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "domain: {}", self.domain)?;
+        writeln!(f, "check_count: {}", self.check_count)?;
+        for (name, pairs) in [("current", &self.current), ("proposed", &self.proposed)] {
+            let Some(pairs) = pairs else {
+                writeln!(f, "{name}: keys unavailable")?;
+                continue;
+            };
+            writeln!(f, "{name} ({} keys):", pairs.len())?;
+            for (key, value) in pairs {
+                writeln!(f, "  {key} = {value}")?;
+            }
+        }
+        for (name, values) in [("pstack", &self.pstack), ("rstack", &self.rstack)] {
+            writeln!(f, "{name} ({} values):", values.len())?;
+            for value in values {
+                writeln!(f, "  {value}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store that can't list its keys
+    #[derive(Debug)]
+    struct Unlisted;
+
+    impl Pairs for Unlisted {
+        fn get(&self, _key: &str) -> Option<Value> {
+            Some(Value::from("zig"))
+        }
+
+        fn put(&mut self, _key: &str, _value: &Value) -> Option<Value> {
+            None
+        }
+
+        fn len(&self) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn test_unlisted_store() {
+        let dump = ContextDump {
+            current: ContextDump::pairs(&Unlisted, true),
+            proposed: Some(Vec::new()),
+            ..Default::default()
+        };
+        assert_eq!(dump.current, None);
+
+        let text = dump.to_string();
+        assert!(text.contains("current: keys unavailable"));
+        assert!(text.contains("proposed (0 keys):"));
+    }
+}
@@ -0,0 +1,44 @@
+//! Error module
+use std::fmt;
+
+/// Errors returned by the [crate::Comrade] API
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComradeError {
+    /// run() was called before a script was loaded
+    NoScriptLoaded,
+    /// The Rhai engine failed to compile the script
+    Compile(String),
+    /// The Rhai engine failed to evaluate the script
+    Eval(String),
+    /// A script requires a function that is not registered
+    MissingFunction(String),
+    /// Text could not be decoded into a [crate::Value]
+    Decode(String),
+    /// The unlock script was run against an empty proposed store
+    EmptyProposed,
+    /// The deadline for verifying passed before verification finished
+    Timeout,
+    /// The current store changed while the unlock script ran
+    CurrentMutated,
+}
+
+impl fmt::Display for ComradeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComradeError::NoScriptLoaded => write!(f, "no script loaded"),
+            ComradeError::Compile(e) => write!(f, "script compilation failed: {e}"),
+            ComradeError::Eval(e) => write!(f, "script evaluation failed: {e}"),
+            ComradeError::MissingFunction(name) => {
+                write!(f, "required function {name} is not registered")
+            }
+            ComradeError::Decode(e) => write!(f, "decoding failed: {e}"),
+            ComradeError::EmptyProposed => write!(f, "the proposed store is empty"),
+            ComradeError::Timeout => write!(f, "verification deadline passed"),
+            ComradeError::CurrentMutated => {
+                write!(f, "the current store changed during unlock")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ComradeError {}
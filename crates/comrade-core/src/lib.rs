@@ -1,28 +1,85 @@
 #![doc = include_str!("../README.md")]
 #![doc = include_str!("../../../README.md")]
 
+#[cfg(feature = "rayon")]
+pub mod batch;
 pub mod context;
+mod dump;
 mod error;
+mod outcome;
 pub mod storage;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod verifier;
+
+#[cfg(feature = "rayon")]
+pub use batch::{verify_batch, EntrySpec};
 
 pub use context::ContextPairs;
 pub use context::Current;
+pub use context::MessageEncoding;
+pub use context::MessageSource;
 pub use context::Proposed;
+pub use dump::ContextDump;
+pub use error::ComradeError;
+pub use outcome::VerifyOutcome;
+pub use storage::pairs::BoxedPairs;
 pub use storage::pairs::Pairs;
 pub use storage::stack::Stack;
 pub use storage::stack::Stk;
 pub use storage::value::Value;
+pub use verifier::Verifier;
 
 use context::Context;
 use parking_lot::Mutex;
-use rhai::Engine;
+use rhai::{Dynamic, Engine, Scope};
 use std::fmt::Debug;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 // Test the README.md code snippets
 #[cfg(doctest)]
 pub struct ReadmeDoctests;
 
+/// Host functions registered by [Comrade::register_unlock]
+const UNLOCK_FUNCTIONS: [&str; 9] = [
+    "push",
+    "push_get",
+    "branch",
+    "push_domain",
+    "pop_domain",
+    "reset_domain",
+    "report",
+    "timestamp",
+    "duration",
+];
+
+/// Host functions registered by [Comrade::register_lock]
+const LOCK_FUNCTIONS: [&str; 22] = [
+    "check_signature",
+    "check_signature_branch",
+    "check_signature_stackkey",
+    "check_signature_recover",
+    "check_signature_any_msg",
+    "check_signature_chain",
+    "check_signature_over_proposed",
+    "check_preimage",
+    "check_preimage_any",
+    "check_preimage_raw",
+    "check_preimage_salted",
+    "check_preimage_prefix",
+    "check_eq",
+    "check_eq_ci",
+    "check_keys_eq",
+    "check_after",
+    "check_before",
+    "value_len",
+    "get",
+    "get_field",
+    "cond",
+    "report",
+];
+
 /// Comrade goes starts at [Initial] Stage, then goes to [Unlocked] Stage.
 #[derive(Debug)]
 pub struct Initial;
@@ -36,9 +93,17 @@ pub trait Pairable: Pairs + Default + Clone + Debug {}
 
 impl<P: Pairs + Default + Clone> Pairable for P {}
 
+/// The store behind [Context]'s current field, which changes between stages.
+///
+/// While the unlock script runs, current holds a copy of the [Proposed] store, so push() reads
+/// from the proposed values. After the unlock script has run, [ComradeBuilder::try_unlock] swaps
+/// in the [Current] store, which the lock scripts check against. [Pairs] calls are forwarded to
+/// whichever store is held, so checks like check_signature work the same in either stage.
 #[derive(Debug, Clone)]
 enum Either<C: Pairable, P: Pairable> {
+    /// The [Current] store, held once the entry is unlocked
     Curr(C),
+    /// The [Proposed] store, held while the unlock script runs
     Prop(P),
 }
 
@@ -61,6 +126,34 @@ impl<C: Pairable, P: Pairable> Pairs for Either<C, P> {
             Either::Prop(p) => p.put(key, value),
         }
     }
+
+    fn compare_and_put(&mut self, key: &str, expected: Option<&Value>, new: &Value) -> bool {
+        match self {
+            Either::Curr(c) => c.compare_and_put(key, expected, new),
+            Either::Prop(p) => p.compare_and_put(key, expected, new),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Either::Curr(c) => c.len(),
+            Either::Prop(p) => p.len(),
+        }
+    }
+
+    fn keys(&self) -> Option<Vec<String>> {
+        match self {
+            Either::Curr(c) => c.keys(),
+            Either::Prop(p) => p.keys(),
+        }
+    }
+
+    fn clone_box(&self) -> Option<Box<dyn Pairs + Send + Sync>> {
+        match self {
+            Either::Curr(c) => c.clone_box(),
+            Either::Prop(p) => p.clone_box(),
+        }
+    }
 }
 
 /// Builder handles building the [Comrade] instance, which allows users to specify the key-path for the branch() function
@@ -71,9 +164,15 @@ pub struct ComradeBuilder<C: Pairable, P: Pairable> {
     current: C,
     /// The unlock script to run
     unlock_script: String,
+    /// Optional handler for the output of Rhai print() and debug() calls
+    print_handler: Option<Arc<dyn Fn(&str) + Send + Sync>>,
+    /// Optional deadline after which running scripts are terminated
+    deadline: Option<Instant>,
+    /// Whether to verify the current store is unchanged after the unlock script runs
+    immutable_current: bool,
 }
 
-impl<C: Pairable + Send + 'static, P: Pairable + Send + 'static> ComradeBuilder<C, P>
+impl<C: Pairable + Send + Sync + 'static, P: Pairable + Send + Sync + 'static> ComradeBuilder<C, P>
 where
     Comrade<Unlocked, C, P>: std::convert::From<Comrade<Initial, C, P>>,
 {
@@ -89,11 +188,59 @@ where
             ))),
             current: (*current).clone(),
             unlock_script: unlock.to_string(),
+            print_handler: None,
+            deadline: None,
+            immutable_current: false,
+        }
+    }
+
+    /// Creates a new [ComradeBuilder] with the given unlock script and empty stores. Set the
+    /// stores with [ComradeBuilder::with_current] and [ComradeBuilder::with_proposed].
+    pub fn from_unlock(unlock: &str) -> Self {
+        Self::new(unlock, Current(C::default()), Proposed(P::default()))
+    }
+
+    /// Inserts a key-value pair into the proposed store before unlocking, so the pair can be
+    /// pushed by the unlock script. The [Current] store is not affected.
+    pub fn put(&mut self, key: &str, value: Value) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            Arc::make_mut(&mut context.current).put(key, &value);
+            Arc::make_mut(&mut context.proposed).put(key, &value);
+        }
+        self
+    }
+
+    /// Inserts each of the key-value pairs into the proposed store, like [ComradeBuilder::put]
+    pub fn put_many(&mut self, pairs: Vec<(String, Value)>) -> &mut Self {
+        for (key, value) in pairs {
+            self.put(&key, value);
+        }
+        self
+    }
+
+    /// Replaces the [Current] store, checked by the lock scripts after unlocking
+    pub fn with_current(&mut self, current: Current<C>) -> &mut Self {
+        self.current = current.0;
+        self
+    }
+
+    /// Replaces the [Proposed] store, read by the unlock script
+    pub fn with_proposed(&mut self, proposed: Proposed<P>) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.current = Arc::new(Either::Prop(proposed.0.clone()));
+            context.proposed = Arc::new(proposed.0);
         }
+        self
     }
 
     /// Optionally set the key-path domain for use in branch() functions.
     ///
+    /// The domain is kept apart from the stores and the other options, so it can be set before
+    /// or after any other builder method and still applies once [ComradeBuilder::try_unlock]
+    /// runs. Read it back with [ComradeBuilder::domain].
+    ///
     /// # Example
     ///
     /// ```rust
@@ -124,10 +271,279 @@ where
         self
     }
 
+    /// The key-path domain branch() will use, "/" unless set with [ComradeBuilder::with_domain]
+    pub fn domain(&self) -> String {
+        self.context.lock().domain.clone()
+    }
+
+    /// Optionally set the maximum number of nested push_domain() calls the unlock script may make.
+    /// Defaults to [context::DEFAULT_MAX_DOMAIN_DEPTH].
+    pub fn with_max_domain_depth(&mut self, depth: usize) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.max_domain_depth = depth;
+        }
+        self
+    }
+
+    /// Optionally cap the size, in bytes, of any single value the unlock script pushes.
+    /// Pushing a larger value fails instead of copying it onto the parameter stack.
+    pub fn with_max_value_size(&mut self, max: usize) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.max_value_size = Some(max);
+        }
+        self
+    }
+
+    /// Optionally define a read-only constant, available by name to both unlock and lock
+    /// scripts. Constants are separate from the [Current] and [Proposed] key-value stores.
+    pub fn with_constant(&mut self, name: &str, value: Value) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.constants.push((name.to_string(), value));
+        }
+        self
+    }
+
+    /// Optionally set which key-value store `check_signature` looks up the signed message from.
+    /// Defaults to [MessageSource::Proposed].
+    pub fn with_message_source(&mut self, source: MessageSource) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.message_source = source;
+        }
+        self
+    }
+
+    /// Optionally replace the clock `check_after` and `check_before` compare timestamps
+    /// against, such as with a fixed time in tests. The clock returns seconds since the unix
+    /// epoch. Defaults to [context::system_clock]. The clock may capture state, such as a
+    /// mock clock a test moves forward.
+    pub fn with_clock(&mut self, clock: Arc<dyn Fn() -> u64 + Send + Sync>) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.clock = context::Clock(clock);
+        }
+        self
+    }
+
+    /// Optionally set how `check_signature` decodes signed messages stored as [Value::Str].
+    /// Defaults to [MessageEncoding::Raw], the UTF-8 bytes of the string.
+    pub fn with_message_encoding(&mut self, encoding: MessageEncoding) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.message_encoding = encoding;
+        }
+        self
+    }
+
+    /// Optionally share the current and proposed stores between the lock attempts of
+    /// [Comrade::try_lock], instead of deep cloning them for every attempt. Lock scripts never
+    /// modify the stores, so only the stacks and counters need to be cloned.
+    pub fn with_shared_stores(&mut self) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.shared_stores = true;
+        }
+        self
+    }
+
+    /// Optionally hex-decode keys stored as [Value::Str] when `check_signature` or `check_preimage`
+    /// expect binary data. By default, only [Value::Bin] keys are accepted.
+    pub fn with_lenient_hex(&mut self) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.lenient_hex = true;
+        }
+        self
+    }
+
+    /// Sets whether [Comrade::dump] leaves out the data of [Value::Bin] and [Value::Str] values,
+    /// describing them only by size. Strings are redacted too, since keys and secrets are often
+    /// stored hex encoded. Redaction is on by default, since dumps are meant to be shared.
+    pub fn with_redaction(&mut self, redact: bool) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.redact = redact;
+        }
+        self
+    }
+
+    /// Optionally emit a `tracing` trace event for every call a script makes to push(), the
+    /// check functions and the other registered functions, with its arguments and result.
+    pub fn with_call_tracing(&mut self) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.call_tracing = true;
+        }
+        self
+    }
+
+    /// Optionally trim surrounding whitespace from the keys scripts pass to push() and the
+    /// check functions, and reject keys that are empty once trimmed. By default, keys are used
+    /// exactly as given.
+    pub fn with_key_normalization(&mut self) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.normalize_keys = true;
+        }
+        self
+    }
+
+    /// Optionally make [ComradeBuilder::try_unlock] fail with [ComradeError::EmptyProposed]
+    /// when the proposed store is empty, instead of only recording a warning. An unlock script
+    /// run against an empty store can't push anything, which usually means a setup bug.
+    pub fn with_required_proposed(&mut self) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.require_proposed = true;
+        }
+        self
+    }
+
+    /// Optionally verify signatures in `check_signature` with a custom [Verifier], such as an
+    /// HSM or remote signer, instead of the built-in multikey verification.
+    pub fn with_verifier(&mut self, verifier: impl Verifier + 'static) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.verifier = Some(Arc::new(verifier));
+        }
+        self
+    }
+
+    /// Optionally record a warning when the unlock script pushes the same key more than once,
+    /// which usually means a script meant to push distinct proofs has a mistake in it.
+    /// Warnings are available from [Comrade::warnings].
+    pub fn with_duplicate_push_warnings(&mut self) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.warn_duplicate_pushes = true;
+        }
+        self
+    }
+
+    /// Optionally pass the output of Rhai print() and debug() calls, in both the unlock and lock
+    /// scripts, to the given handler. Without a handler, script output is discarded.
+    pub fn with_print_handler(
+        &mut self,
+        handler: impl Fn(&str) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.print_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Optionally terminate the unlock script, and the scripts of every later lock attempt,
+    /// once the deadline passes. A terminated script fails with [ComradeError::Timeout].
+    pub fn with_deadline(&mut self, deadline: Instant) -> &mut Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Optionally discard the output of Rhai print() and debug() calls, so untrusted scripts
+    /// can't cause any I/O through them. Replaces any handler set before.
+    pub fn with_silent(&mut self) -> &mut Self {
+        self.with_print_handler(|_| {})
+    }
+
+    /// Optionally run the unlock script in combined mode, as a single script that both pushes
+    /// proofs from the proposed store and checks them against the current store.
+    /// [ComradeBuilder::try_unlock] then registers the unlock and lock functions on one engine
+    /// and runs the script once. Its check results are on the return stack of the returned
+    /// [Comrade], in [Comrade::returns].
+    ///
+    /// This bypasses the two-phase model, so only use it with trusted scripts. Normally the
+    /// untrusted unlock script only supplies proofs, and the lock script, which the verifier
+    /// controls, decides what they must satisfy. A combined script does both, so whoever writes
+    /// it can simply leave out any check it would fail.
+    pub fn combined(&mut self) -> &mut Self {
+        {
+            let mut context = self.context.lock();
+            context.combined = true;
+        }
+        self
+    }
+
+    /// Optionally verify that the unlock script leaves the [Current] store unchanged.
+    /// [ComradeBuilder::try_unlock] snapshots the store before the script runs and fails with
+    /// [ComradeError::CurrentMutated] if it differs afterwards.
+    ///
+    /// Outside [ComradeBuilder::combined] mode the unlock script never sees the current store,
+    /// so the check always passes. In combined mode the script runs against the real current
+    /// store. No registered function writes to it, but a [Pairs] store that changes as it is
+    /// read would be caught. Stores that can't list their keys are compared by length only.
+    pub fn with_immutable_current(&mut self) -> &mut Self {
+        self.immutable_current = true;
+        self
+    }
+
+    /// Optionally log the output of Rhai print() and debug() calls with `tracing::debug!`
+    pub fn with_tracing_on_print(&mut self) -> &mut Self {
+        self.with_print_handler(|msg| tracing::debug!("[RHAI]: {}", msg))
+    }
+
+    /// Evaluates the unlock script without touching the context, with the unlock functions
+    /// replaced by no-ops. Use this to check that an untrusted unlock script runs cleanly before
+    /// running it for real with [ComradeBuilder::try_unlock]. A deadline set with
+    /// [ComradeBuilder::with_deadline] applies to the dry run too, failing with
+    /// [ComradeError::Timeout].
+    pub fn try_unlock_dry(&self) -> Result<(), ComradeError> {
+        let mut engine = Engine::new_raw();
+        engine.register_fn("push", |_: &str| true);
+        engine.register_fn("push_get", |_: &str| Dynamic::UNIT);
+        engine.register_fn("branch", |key: &str| key.to_string());
+        engine.register_fn("push_domain", |_: &str| true);
+        engine.register_fn("pop_domain", || true);
+        engine.register_fn("reset_domain", || true);
+        engine.register_fn("report", |_: &str, _: Dynamic| ());
+        engine.register_fn("timestamp", timestamp);
+        engine.register_fn("duration", duration);
+
+        // the same deadline as try_unlock, so a dry run of a script that never ends stops too
+        if let Some(deadline) = self.deadline {
+            engine.on_progress(move |_| (Instant::now() >= deadline).then_some(Dynamic::UNIT));
+        }
+
+        let mut scope = Scope::new();
+        for (name, value) in self.context.lock().constants.iter() {
+            scope.push_constant_dynamic(name.as_str(), value.clone().into());
+        }
+
+        engine
+            .eval_with_scope::<Dynamic>(&mut scope, &self.unlock_script)
+            .map(|_| ())
+            .map_err(|e| match *e {
+                rhai::EvalAltResult::ErrorTerminated(..) => ComradeError::Timeout,
+                e => ComradeError::Eval(e.to_string()),
+            })
+    }
+
     /// Builds the [Comrade<Unlocked>] instance and runs the unlock script with the given context and entries.
-    pub fn try_unlock(&mut self) -> Result<Comrade<Unlocked, C, P>, Box<dyn std::error::Error>> {
-        // take the context and move it out of self.context
-        let ctx: Context<C, P> = self.context.lock().clone();
+    ///
+    /// The [Current] store is held by the builder, out of the unlock script's reach, until the
+    /// unlock script has run, so the unlock script can't read or modify it. The exception is
+    /// [ComradeBuilder::combined] mode, where the script runs against the real current store
+    /// so it can check proofs. Use [ComradeBuilder::with_immutable_current] to verify it is
+    /// left unchanged.
+    ///
+    /// Unlocking is always transactional, so there is no opt-in switch for it. The unlock
+    /// script runs on a copy of the builder's context, so if it errors partway through, the
+    /// pushes and domain changes it made are discarded and the builder is left as it was, ready
+    /// to be unlocked again. Unlock scripts never write to the stores, so the copy shares them
+    /// with the builder, and only the stacks and settings are cloned per unlock.
+    pub fn try_unlock(&mut self) -> Result<Comrade<Unlocked, C, P>, ComradeError> {
+        // stage the unlock on a copy of the context, which is only kept if the script succeeds.
+        // The stores are shared, since put() on the builder copies them before writing.
+        let mut ctx: Context<C, P> = self.context.lock().clone_sharing(true);
+
+        // the warning goes on the copy, so retrying the builder doesn't repeat it
+        if ctx.proposed.is_empty() {
+            if ctx.require_proposed {
+                return Err(ComradeError::EmptyProposed);
+            }
+            tracing::warn!("try_unlock: the proposed store is empty");
+            ctx.warnings.push("the proposed store is empty".to_string());
+        }
         let mut comrade = Comrade::new(ctx);
 
         // if test, set engine on_print
@@ -136,31 +552,85 @@ where
             tracing::debug!("[RHAI]: {}", msg);
         });
 
-        // move the unlock script into the Comrade instance
+        // the handler goes in the engine config, so lock attempts use it too
+        if let Some(handler) = self.print_handler.clone() {
+            comrade.with_engine_config(move |engine| {
+                let print = Arc::clone(&handler);
+                engine.on_print(move |msg| print(msg));
+                let debug = Arc::clone(&handler);
+                engine.on_debug(move |msg, _, _| debug(msg));
+            });
+        }
+
+        // Rhai checks for termination as the script runs, so a long script stops on time
+        if let Some(deadline) = self.deadline {
+            comrade.with_engine_config(move |engine| {
+                engine.on_progress(move |_| (Instant::now() >= deadline).then_some(Dynamic::UNIT));
+            });
+        }
+
+        if comrade.context.lock().combined {
+            // the checks need the real current store from the start, and push() reads the
+            // proposed store instead
+            comrade.context.lock().current = Arc::new(Either::Curr(self.current.clone()));
+            let before = self.immutable_current.then(|| snapshot(&self.current));
+            let mut combined: Comrade<Unlocked, C, P> = comrade.into();
+            combined.load(self.unlock_script.clone()).run()?;
+            if let Some(before) = before {
+                if before != snapshot(combined.context.lock().current.as_ref()) {
+                    return Err(ComradeError::CurrentMutated);
+                }
+            }
+            return Ok(combined);
+        }
+
+        // copy the unlock script into the Comrade instance, keeping it for a retry,
         // and run the unlock script called "for_great_justice"
-        comrade
-            .load(std::mem::take(&mut self.unlock_script))
-            .run()?;
+        comrade.load(self.unlock_script.clone()).run()?;
 
         // During unlock, both the current and proposed are set to the proposed value.
         // after unlock has run, take the current to set the current value.
         // We can take the current value because the unlock script has already run, and only
         // runs once.
-        comrade.context.lock().current = Either::Curr(self.current.clone());
+        comrade.context.lock().current = Arc::new(Either::Curr(self.current.clone()));
 
         Ok(comrade.into())
     }
 }
 
-/// Switches the [std::marker::PhantomData] to [Unlocked] Stage
-impl<C: Pairable, P: Pairable> From<Comrade<Initial, C, P>> for Comrade<Unlocked, C, P> {
+/// The length and [Pairs::canonical_bytes] of a store, compared by
+/// [ComradeBuilder::with_immutable_current]
+fn snapshot(store: &impl Pairs) -> (usize, Option<Vec<u8>>) {
+    (store.len(), store.canonical_bytes(&[]))
+}
+
+/// Switches the [std::marker::PhantomData] to [Unlocked] Stage, and registers the lock
+/// functions so lock scripts can be [Comrade::run] straight away.
+impl<C: Pairable + Send + Sync + 'static, P: Pairable + Send + Sync + 'static>
+    From<Comrade<Initial, C, P>> for Comrade<Unlocked, C, P>
+{
     fn from(comrade: Comrade<Initial, C, P>) -> Self {
-        Comrade {
+        let mut unlocked = Comrade {
             context: comrade.context,
             engine: comrade.engine,
             script: comrade.script,
+            functions: comrade.functions,
+            engine_config: comrade.engine_config,
             stage: std::marker::PhantomData,
-        }
+        };
+        unlocked.register_lock();
+        unlocked
+    }
+}
+
+/// Customizations from [Comrade::with_engine_config], kept so they can be re-applied to the
+/// fresh [Engine] each lock attempt runs on
+#[derive(Clone, Default)]
+struct EngineConfig(Vec<Arc<dyn Fn(&mut Engine) + Send + Sync>>);
+
+impl Debug for EngineConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EngineConfig({} customizations)", self.0.len())
     }
 }
 
@@ -170,10 +640,14 @@ pub struct Comrade<Stage, C: Pairable, P: Pairable> {
     context: Arc<Mutex<Context<C, P>>>,
     engine: Arc<Mutex<Engine>>,
     script: Option<String>,
+    functions: Vec<&'static str>,
+    engine_config: EngineConfig,
     stage: std::marker::PhantomData<Stage>,
 }
 
-impl<C: Pairable + Send + 'static, P: Pairable + Send + 'static> Comrade<Initial, C, P> {
+impl<C: Pairable + Send + Sync + 'static, P: Pairable + Send + Sync + 'static>
+    Comrade<Initial, C, P>
+{
     /// Create a new Comrade instance with the given [Context].
     /// Can only be used to create a Comrade instance at the [Initial] Stage.
     pub fn new(ctx: Context<C, P>) -> Self {
@@ -184,6 +658,8 @@ impl<C: Pairable + Send + 'static, P: Pairable + Send + 'static> Comrade<Initial
             context: Arc::clone(&context),
             engine: Arc::new(Mutex::new(engine)),
             script: None,
+            functions: Vec::new(),
+            engine_config: EngineConfig::default(),
             stage: std::marker::PhantomData,
         };
 
@@ -192,62 +668,337 @@ impl<C: Pairable + Send + 'static, P: Pairable + Send + 'static> Comrade<Initial
         comrade
     }
 
-    /// Registers just the unlock functions (push, branch) to the [Context] Rhai [Engine].
-    /// Unock functions are only available at the [Initial] Stage.
+    /// Registers just the unlock functions (push, push_get, branch, push_domain, pop_domain,
+    /// reset_domain, report, and the timestamp and duration value constructors) to the
+    /// [Context] Rhai [Engine].
+    /// Unlock functions are registered at the [Initial] Stage, and stay registered on the
+    /// engines lock scripts run on.
     pub fn register_unlock(&mut self) {
         let push = {
             let context = Arc::clone(&self.context);
             move |key: String| {
                 let mut context = context.lock();
-                context.push(&key)
+                context.traced("push", &[&key], |context| context.push(&key))
+            }
+        };
+
+        let push_get = {
+            let context = Arc::clone(&self.context);
+            move |key: String| -> Dynamic {
+                let mut context = context.lock();
+                context
+                    .traced("push_get", &[&key], |context| context.push_get(&key))
+                    .map_or(Dynamic::UNIT, Into::into)
             }
         };
 
         let branch = {
             let context = Arc::clone(&self.context);
             move |key: &str| {
-                let context = context.lock();
-                context.branch(key)
+                let mut context = context.lock();
+                context.traced("branch", &[key], |context| context.branch(key))
+            }
+        };
+
+        let push_domain = {
+            let context = Arc::clone(&self.context);
+            move |segment: &str| {
+                let mut context = context.lock();
+                context.traced("push_domain", &[segment], |context| {
+                    context.push_domain(segment)
+                })
+            }
+        };
+
+        let pop_domain = {
+            let context = Arc::clone(&self.context);
+            move || {
+                let mut context = context.lock();
+                context.traced("pop_domain", &[], |context| context.pop_domain())
+            }
+        };
+
+        let reset_domain = {
+            let context = Arc::clone(&self.context);
+            move || {
+                let mut context = context.lock();
+                context.traced("reset_domain", &[], |context| context.reset_domain())
+            }
+        };
+
+        let report = {
+            let context = Arc::clone(&self.context);
+            move |label: &str, value: Dynamic| {
+                let mut context = context.lock();
+                let value = Value::from(value);
+                context.traced("report", &[label, &format!("{value:?}")], |context| {
+                    context.report(label, value)
+                })
             }
         };
 
         self.engine.lock().register_fn("push", push);
+        self.engine.lock().register_fn("push_get", push_get);
         self.engine.lock().register_fn("branch", branch);
+        self.engine.lock().register_fn("push_domain", push_domain);
+        self.engine.lock().register_fn("pop_domain", pop_domain);
+        self.engine.lock().register_fn("reset_domain", reset_domain);
+        self.engine.lock().register_fn("report", report);
+        self.engine.lock().register_fn("timestamp", timestamp);
+        self.engine.lock().register_fn("duration", duration);
+
+        self.registered(&UNLOCK_FUNCTIONS);
     }
 }
 
 impl<Stage, C: Pairable, P: Pairable> Comrade<Stage, C, P> {
     /// Loads a lock script into Comrade
-    pub fn load(&mut self, script: String) -> &mut Self {
-        self.script = Some(script);
+    pub fn load(&mut self, script: impl Into<String>) -> &mut Self {
+        self.script = Some(script.into());
+        self
+    }
+
+    /// The non-fatal warnings recorded while running scripts
+    pub fn warnings(&self) -> Vec<String> {
+        self.context.lock().warnings.clone()
+    }
+
+    /// The labeled results scripts run on this instance recorded with report(label, value),
+    /// in order. Reports from the unlock script carry over to the [Unlocked] stage. Each
+    /// [Comrade::try_lock] attempt runs on its own clone, so to collect a lock script's
+    /// reports, run it on a clone with [Comrade::load] and [Comrade::run].
+    pub fn script_reports(&self) -> Vec<(String, Value)> {
+        self.context.lock().reports.clone()
+    }
+
+    /// A snapshot of the stores, stacks, domain and check count, for attaching to a bug report.
+    /// Binary and string values are redacted unless [ComradeBuilder::with_redaction] turned it
+    /// off. Stores that don't implement [Pairs::keys] are dumped as unlisted. While
+    /// the unlock script runs, the current store holds a copy of the proposed store.
+    pub fn dump(&self) -> ContextDump {
+        let context = self.context.lock();
+        let redact = context.redact;
+        ContextDump {
+            current: ContextDump::pairs(context.current.as_ref(), redact),
+            proposed: ContextDump::pairs(context.proposed.as_ref(), redact),
+            pstack: ContextDump::values(context.pstack.iter(), redact),
+            rstack: ContextDump::values(context.rstack.iter(), redact),
+            domain: context.domain.clone(),
+            check_count: context.check_count,
+        }
+    }
+
+    /// The most recent [Value::Failure] message on the return stack, if any
+    pub fn last_failure(&self) -> Option<String> {
+        self.context
+            .lock()
+            .rstack
+            .iter()
+            .rev()
+            .find_map(|value| match value {
+                Value::Failure(err) => Some(err.clone()),
+                _ => None,
+            })
+    }
+
+    /// The number of key-value pairs in the current store
+    pub fn current_len(&self) -> usize {
+        self.context.lock().current_len()
+    }
+
+    /// The number of key-value pairs in the proposed store
+    pub fn proposed_len(&self) -> usize {
+        self.context.lock().proposed_len()
+    }
+
+    /// Checks that every function a script declares it requires is registered.
+    ///
+    /// Requirements are declared in the script's leading comments, for example:
+    ///
+    /// ```text
+    /// // comrade: requires check_signature, check_preimage
+    /// check_signature("/pubkey", "/entry/") || check_preimage("/hash")
+    /// ```
+    pub fn check_requirements(&self, script: &str) -> Result<(), ComradeError> {
+        match requirements(script)
+            .into_iter()
+            .find(|name| !self.functions.iter().any(|f| f == name))
+        {
+            Some(name) => Err(ComradeError::MissingFunction(name.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Compiles each of the lock scripts without running them, so an entry can be rejected up
+    /// front. Returns the index and error of every lock that fails to compile.
+    pub fn compile_all(&self, locks: &[String]) -> Result<(), Vec<(usize, ComradeError)>> {
+        let engine = self.engine.lock();
+        let errors: Vec<(usize, ComradeError)> = locks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, lock)| {
+                engine
+                    .compile(lock)
+                    .err()
+                    .map(|e| (i, ComradeError::Compile(e.to_string())))
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The names of the host functions the crate has registered on the engine: the unlock
+    /// functions at the [Initial] stage, plus the lock functions at the [Unlocked] stage
+    pub fn registered_functions(&self) -> Vec<String> {
+        self.functions.iter().map(|f| f.to_string()).collect()
+    }
+
+    /// Records the names of host functions registered on the engine
+    fn registered(&mut self, names: &[&'static str]) {
+        for name in names {
+            if !self.functions.contains(name) {
+                self.functions.push(name);
+            }
+        }
+    }
+
+    /// Escape hatch to customize the underlying Rhai [Engine], such as registering custom
+    /// types, operators or functions.
+    ///
+    /// The customization is also re-applied to the fresh engine each [Comrade::try_lock]
+    /// attempt runs on.
+    ///
+    /// Use with care: re-registering a function the crate registers (push, check_signature,
+    /// etc.) replaces it, which can silently change the meaning of lock and unlock scripts.
+    pub fn with_engine_config(
+        &mut self,
+        f: impl Fn(&mut Engine) + Send + Sync + 'static,
+    ) -> &mut Self {
+        f(&mut self.engine.lock());
+        self.engine_config.0.push(Arc::new(f));
+        self
+    }
+
+    /// Returns the currently loaded script, if any
+    pub fn script(&self) -> Option<&str> {
+        self.script.as_deref()
+    }
+
+    /// Clears the loaded script, so run() errors with [ComradeError::NoScriptLoaded]
+    pub fn clear_script(&mut self) -> &mut Self {
+        self.script = None;
         self
     }
 
     /// Evaluate the Rhai script function with the given name
-    pub fn run(&mut self) -> Result<bool, String> {
+    pub fn run(&mut self) -> Result<bool, ComradeError> {
         // get unlock script, if None return error
-        let script = self.script.as_ref().ok_or("no script loaded")?;
+        let script = self.script.as_ref().ok_or(ComradeError::NoScriptLoaded)?;
+
+        // expose the constants to the script, without holding the context lock during eval
+        let mut scope = Scope::new();
+        for (name, value) in self.context.lock().constants.iter() {
+            scope.push_constant_dynamic(name.as_str(), value.clone().into());
+        }
 
-        let result = self.engine.lock().eval(script).map_err(|e| e.to_string())?;
+        let result = self
+            .engine
+            .lock()
+            .eval_with_scope(&mut scope, script)
+            .map_err(|e| match *e {
+                // only a deadline set with_deadline terminates scripts
+                rhai::EvalAltResult::ErrorTerminated(..) => ComradeError::Timeout,
+                e => ComradeError::Eval(e.to_string()),
+            })?;
 
         Ok(result)
     }
 }
 
 /// Methods available at [Unlocked] Stage
-impl<C: Pairable + Send + 'static, P: Pairable + Send + 'static> Comrade<Unlocked, C, P> {
+impl<C: Pairable + Send + Sync + 'static, P: Pairable + Send + Sync + 'static>
+    Comrade<Unlocked, C, P>
+{
     /// Returns the return Stack
     pub fn returns(&self) -> Stk {
         self.context.lock().rstack.clone()
     }
 
-    /// Registers just the lock functions (check_signature, check_preimage)
+    /// Consumes the [Comrade] and returns its proposed store as the [Current] store for the
+    /// next entry in a chain, once this entry has verified
+    pub fn into_next_current(self) -> Current<P> {
+        let proposed = (*self.context.lock().proposed).clone();
+        Current(proposed)
+    }
+
+    /// Returns the parameter Stack, holding the values the unlock script pushed for the lock
+    /// scripts to consume
+    pub fn pstack(&self) -> Stk {
+        self.context.lock().pstack.clone()
+    }
+
+    /// Registers just the lock functions (check_signature, check_preimage, etc.)
+    ///
+    /// Each check runs through [Context::guarded], so a check that panics leaves the stacks as
+    /// they were before the check and records a failure instead. The other functions run
+    /// through [Context::traced].
     pub fn register_lock(&mut self) {
         let check_signature = {
             let context = Arc::clone(&self.context);
             move |key: &str, msg: &str| {
                 let mut context = context.lock();
-                context.check_signature(key, msg)
+                context.guarded("check_signature", &[key, msg], |context| {
+                    context.check_signature(key, msg)
+                })
+            }
+        };
+
+        let check_signature_branch = {
+            let context = Arc::clone(&self.context);
+            move |key: &str, msg: &str| {
+                let mut context = context.lock();
+                context.guarded("check_signature_branch", &[key, msg], |context| {
+                    context.check_signature_branch(key, msg)
+                })
+            }
+        };
+
+        let check_signature_stackkey = {
+            let context = Arc::clone(&self.context);
+            move |msg: &str| {
+                let mut context = context.lock();
+                context.guarded("check_signature_stackkey", &[msg], |context| {
+                    context.check_signature_stackkey(msg)
+                })
+            }
+        };
+
+        let check_signature_recover = {
+            let context = Arc::clone(&self.context);
+            move |msg: &str, expected_address_key: &str| {
+                let mut context = context.lock();
+                context.guarded(
+                    "check_signature_recover",
+                    &[msg, expected_address_key],
+                    |context| context.check_signature_recover(msg, expected_address_key),
+                )
+            }
+        };
+
+        let check_signature_any_msg = {
+            let context = Arc::clone(&self.context);
+            move |key: &str, msgs: rhai::Array| -> Result<bool, Box<rhai::EvalAltResult>> {
+                let msgs = strings("check_signature_any_msg", msgs)?;
+                let mut context = context.lock();
+                Ok(context.guarded(
+                    "check_signature_any_msg",
+                    &[key, &msgs.join(", ")],
+                    |context| context.check_signature_any_msg(key, &msgs),
+                ))
             }
         };
 
@@ -255,38 +1006,374 @@ impl<C: Pairable + Send + 'static, P: Pairable + Send + 'static> Comrade<Unlocke
             let context = Arc::clone(&self.context);
             move |key: String| {
                 let mut context = context.lock();
-                context.check_preimage(key)
+                context.guarded("check_preimage", &[&key], |context| {
+                    context.check_preimage(key.clone())
+                })
+            }
+        };
+
+        let check_eq = {
+            let context = Arc::clone(&self.context);
+            move |key: &str| {
+                let mut context = context.lock();
+                context.guarded("check_eq", &[key], |context| context.check_eq(key))
+            }
+        };
+
+        let check_keys_eq = {
+            let context = Arc::clone(&self.context);
+            move |key_a: &str, key_b: &str| {
+                let mut context = context.lock();
+                context.guarded("check_keys_eq", &[key_a, key_b], |context| {
+                    context.check_keys_eq(key_a, key_b)
+                })
+            }
+        };
+
+        let check_eq_ci = {
+            let context = Arc::clone(&self.context);
+            move |key: &str| {
+                let mut context = context.lock();
+                context.guarded("check_eq_ci", &[key], |context| context.check_eq_ci(key))
+            }
+        };
+
+        let check_after = {
+            let context = Arc::clone(&self.context);
+            move |key: &str| {
+                let mut context = context.lock();
+                context.guarded("check_after", &[key], |context| context.check_after(key))
+            }
+        };
+
+        let check_before = {
+            let context = Arc::clone(&self.context);
+            move |key: &str| {
+                let mut context = context.lock();
+                context.guarded("check_before", &[key], |context| context.check_before(key))
+            }
+        };
+
+        let check_preimage_raw = {
+            let context = Arc::clone(&self.context);
+            move |key: &str, algo: &str| {
+                let mut context = context.lock();
+                context.guarded("check_preimage_raw", &[key, algo], |context| {
+                    context.check_preimage_raw(key, algo)
+                })
+            }
+        };
+
+        let check_signature_chain = {
+            let context = Arc::clone(&self.context);
+            move |key: &str, msg: &str| {
+                let mut context = context.lock();
+                context.guarded("check_signature_chain", &[key, msg], |context| {
+                    context.check_signature_chain(key, msg)
+                })
+            }
+        };
+
+        let check_signature_over_proposed = {
+            let context = Arc::clone(&self.context);
+            move |key: &str, proof_key: &str| {
+                let mut context = context.lock();
+                context.guarded(
+                    "check_signature_over_proposed",
+                    &[key, proof_key],
+                    |context| context.check_signature_over_proposed(key, proof_key),
+                )
+            }
+        };
+
+        let check_preimage_prefix = {
+            let context = Arc::clone(&self.context);
+            move |key: &str, len: rhai::INT| {
+                let mut context = context.lock();
+                context.guarded(
+                    "check_preimage_prefix",
+                    &[key, &len.to_string()],
+                    |context| match usize::try_from(len) {
+                        Ok(len) => context.check_preimage_prefix(key, len),
+                        Err(_) => {
+                            context.check_fail(&format!("invalid digest prefix length: {len}"))
+                        }
+                    },
+                )
+            }
+        };
+
+        let check_preimage_salted = {
+            let context = Arc::clone(&self.context);
+            move |hash_key: &str, salt_key: &str| {
+                let mut context = context.lock();
+                context.guarded("check_preimage_salted", &[hash_key, salt_key], |context| {
+                    context.check_preimage_salted(hash_key, salt_key)
+                })
+            }
+        };
+
+        let check_preimage_any = {
+            let context = Arc::clone(&self.context);
+            move |keys: rhai::Array| -> Result<bool, Box<rhai::EvalAltResult>> {
+                let keys = strings("check_preimage_any", keys)?;
+                let mut context = context.lock();
+                Ok(
+                    context.guarded("check_preimage_any", &[&keys.join(", ")], |context| {
+                        context.check_preimage_any(&keys)
+                    }),
+                )
+            }
+        };
+
+        let get = {
+            let context = Arc::clone(&self.context);
+            move |key: &str| -> Dynamic {
+                let mut context = context.lock();
+                context
+                    .traced("get", &[key], |context| context.lookup(key))
+                    .map_or(Dynamic::UNIT, Into::into)
+            }
+        };
+
+        let get_field = {
+            let context = Arc::clone(&self.context);
+            move |key: &str, field: &str| -> Dynamic {
+                let mut context = context.lock();
+                context
+                    .traced("get_field", &[key, field], |context| {
+                        context.get_field(key, field)
+                    })
+                    .map_or(Dynamic::UNIT, Into::into)
+            }
+        };
+
+        let cond = {
+            let context = Arc::clone(&self.context);
+            move |test: bool, then_key: &str, else_key: &str| {
+                let mut context = context.lock();
+                context.traced(
+                    "cond",
+                    &[&test.to_string(), then_key, else_key],
+                    |context| context.cond(test, then_key, else_key),
+                )
+            }
+        };
+
+        let value_len = {
+            let context = Arc::clone(&self.context);
+            move |key: &str| -> Result<rhai::INT, Box<rhai::EvalAltResult>> {
+                let mut context = context.lock();
+                let len = context.traced("value_len", &[key], |context| context.value_len(key));
+                rhai::INT::try_from(len).map_err(|_| {
+                    format!("value for {key} is too large for value_len: {len}").into()
+                })
+            }
+        };
+
+        let report = {
+            let context = Arc::clone(&self.context);
+            move |label: &str, value: Dynamic| {
+                let mut context = context.lock();
+                let value = Value::from(value);
+                context.traced("report", &[label, &format!("{value:?}")], |context| {
+                    context.report(label, value)
+                })
             }
         };
 
         self.engine
             .lock()
             .register_fn("check_signature", check_signature);
+        self.engine
+            .lock()
+            .register_fn("check_signature_branch", check_signature_branch);
+        self.engine
+            .lock()
+            .register_fn("check_signature_stackkey", check_signature_stackkey);
+        self.engine
+            .lock()
+            .register_fn("check_signature_recover", check_signature_recover);
+        self.engine
+            .lock()
+            .register_fn("check_signature_any_msg", check_signature_any_msg);
+        self.engine
+            .lock()
+            .register_fn("check_signature_chain", check_signature_chain);
+        self.engine.lock().register_fn(
+            "check_signature_over_proposed",
+            check_signature_over_proposed,
+        );
         self.engine
             .lock()
             .register_fn("check_preimage", check_preimage);
+        self.engine
+            .lock()
+            .register_fn("check_preimage_any", check_preimage_any);
+        self.engine
+            .lock()
+            .register_fn("check_preimage_raw", check_preimage_raw);
+        self.engine
+            .lock()
+            .register_fn("check_preimage_salted", check_preimage_salted);
+        self.engine
+            .lock()
+            .register_fn("check_preimage_prefix", check_preimage_prefix);
+        self.engine.lock().register_fn("check_eq", check_eq);
+        self.engine.lock().register_fn("check_eq_ci", check_eq_ci);
+        self.engine
+            .lock()
+            .register_fn("check_keys_eq", check_keys_eq);
+        self.engine.lock().register_fn("check_after", check_after);
+        self.engine.lock().register_fn("check_before", check_before);
+        self.engine.lock().register_fn("value_len", value_len);
+        self.engine.lock().register_fn("get", get);
+        self.engine.lock().register_fn("get_field", get_field);
+        self.engine.lock().register_fn("cond", cond);
+        self.engine.lock().register_fn("report", report);
+
+        self.registered(&LOCK_FUNCTIONS);
     }
 
     /// Try the given lock script. Clones the current context and runs the lock script on the clone.
-    pub fn try_lock(&self, lock: String) -> Result<Option<Value>, String> {
-        // We want to re-use expensive Rhai Engine, but clone pstack and rstack for each lock try.
-        // In order to do that, we would need to re-register the engine to the inner context of the clone.
+    /// Returns the top of the return stack, which is the result of the last evaluated check.
+    ///
+    /// Lock attempts may run concurrently from several threads. Each attempt runs on its own
+    /// cloned context and its own fresh Rhai [Engine], so attempts neither block each other
+    /// nor see each other's stacks. The unlocked context is only locked briefly to clone it.
+    /// The fresh engine has the same functions as this one, so lock scripts can call push()
+    /// and the other unlock functions as well as the checks.
+    pub fn try_lock(&self, lock: impl Into<String>) -> Result<Option<Value>, ComradeError> {
+        let mut cloned = self.clone();
+
+        // load lock script, run move_every_zig
+        cloned.load(lock).run()?;
+
+        // check the context rstack top, return the result
+        let top = cloned.context.lock().rstack.top();
+        Ok(top)
+    }
+
+    /// Check a signature natively, without Rhai. Equivalent to
+    /// `try_lock(r#"check_signature(key, msg)"#)`, and returns the same [Value], but skips
+    /// building an engine and evaluating a script for the common one-check lock.
+    pub fn try_lock_signature(&self, key: &str, msg: &str) -> Option<Value> {
+        let mut context = self.context.lock().clone();
+        context.guarded("check_signature", &[key, msg], |context| {
+            context.check_signature(key, msg)
+        });
+        context.rstack.top()
+    }
+
+    /// Try the given lock script like [Comrade::try_lock], also returning the number of
+    /// check_* operations that ran during this attempt.
+    pub fn try_lock_counted(
+        &self,
+        lock: impl Into<String>,
+    ) -> Result<(Option<Value>, usize), ComradeError> {
         let cloned_inner_context = self.context.lock().clone();
-        let mut cloned = Comrade::<Unlocked, C, P> {
-            context: Arc::new(Mutex::new(cloned_inner_context)),
-            engine: self.engine.clone(),
-            script: self.script.clone(),
-            stage: std::marker::PhantomData,
-        };
+        let before = cloned_inner_context.checks_run;
+        let (top, checks_run) = self.try_lock_in(cloned_inner_context, lock.into())?;
+
+        Ok((top, checks_run - before))
+    }
 
-        cloned.register_lock();
+    /// Try the given lock script like [Comrade::try_lock_counted], flattening the result into a
+    /// [VerifyOutcome]. A script error is still returned as an error.
+    pub fn try_lock_outcome(&self, lock: impl Into<String>) -> Result<VerifyOutcome, ComradeError> {
+        let (result, check_count) = self.try_lock_counted(lock)?;
+        Ok(VerifyOutcome::new(result, check_count))
+    }
+
+    /// Try the given lock script against a different [Current] key-value store. Clones the
+    /// context, swaps in the given current store, and runs the lock script on the clone.
+    pub fn try_lock_with_current(
+        &self,
+        lock: impl Into<String>,
+        current: Current<C>,
+    ) -> Result<Option<Value>, ComradeError> {
+        let mut cloned_inner_context = self.context.lock().clone();
+        cloned_inner_context.current = Arc::new(Either::Curr(current.0));
+        let (top, _) = self.try_lock_in(cloned_inner_context, lock.into())?;
+
+        Ok(top)
+    }
+
+    /// Try the given lock script under each of the given domains in turn, for unlock proofs that
+    /// may be valid under one of several branches of a fork tree. Each attempt runs on a clone
+    /// of the context with that domain set. Returns the first domain the lock succeeds under,
+    /// with its [Value::Success], or `None` if it fails under all of them.
+    pub fn try_lock_over_domains(
+        &self,
+        lock: impl Into<String>,
+        domains: &[String],
+    ) -> Result<Option<(String, Value)>, ComradeError> {
+        let lock = lock.into();
+        for domain in domains {
+            let mut cloned_inner_context = self.context.lock().clone();
+            cloned_inner_context.domain = domain.clone();
+            let (top, _) = self.try_lock_in(cloned_inner_context, lock.clone())?;
+
+            if let Some(value @ Value::Success(_)) = top {
+                return Ok(Some((domain.clone(), value)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs the lock script on the given cloned context, on a fresh Rhai Engine.
+    /// Returns the top of the return stack and the number of checks run, as left by the lock
+    /// script.
+    fn try_lock_in(
+        &self,
+        cloned_inner_context: Context<C, P>,
+        lock: String,
+    ) -> Result<(Option<Value>, usize), ComradeError> {
+        let mut cloned = self.with_context(cloned_inner_context);
 
         // load lock script, run move_every_zig
         cloned.load(lock).run()?;
 
-        // check the context rstack top, return the result
-        let res = cloned.context.lock().rstack.top();
-        Ok(res)
+        // read the results in place, rather than cloning the stores again
+        let context = cloned.context.lock();
+        Ok((context.rstack.top(), context.checks_run))
+    }
+
+    /// Builds an independent [Unlocked] Comrade around the given context, on a fresh Rhai Engine
+    /// with the same functions as this one registered against that context.
+    fn with_context(&self, context: Context<C, P>) -> Self {
+        // Sharing one engine would serialize concurrent attempts on the engine mutex, and each
+        // attempt re-registers the functions against its own cloned context. A raw engine is
+        // cheap to build, so give every attempt its own, with the same customizations.
+        let mut engine = Engine::new_raw();
+        for f in self.engine_config.0.iter() {
+            f(&mut engine);
+        }
+
+        // register the unlock functions, then the lock functions, the same way an unlocked
+        // Comrade is built, so lock scripts can still call push(), branch() and the rest
+        let mut cloned = Comrade::<Initial, C, P> {
+            context: Arc::new(Mutex::new(context)),
+            engine: Arc::new(Mutex::new(engine)),
+            script: self.script.clone(),
+            functions: Vec::new(),
+            engine_config: self.engine_config.clone(),
+            stage: std::marker::PhantomData,
+        };
+
+        cloned.register_unlock();
+        cloned.into()
+    }
+}
+
+/// Clones the context into a fresh `Arc<Mutex<..>>` and builds a fresh engine with the unlock and
+/// lock functions registered against it, so the clone is independent of the original.
+impl<C: Pairable + Send + Sync + 'static, P: Pairable + Send + Sync + 'static> Clone
+    for Comrade<Unlocked, C, P>
+{
+    fn clone(&self) -> Self {
+        let context = self.context.lock().clone();
+        self.with_context(context)
     }
 }
 
@@ -296,6 +1383,107 @@ impl<Stage, C: Pairable, P: Pairable> From<&Comrade<Stage, C, P>> for Context<C,
     }
 }
 
+/// Makes a [Value::Timestamp] from a number of seconds since the unix epoch, for scripts
+fn timestamp(secs: rhai::INT) -> Result<Dynamic, Box<rhai::EvalAltResult>> {
+    u64::try_from(secs)
+        .map(|secs| Dynamic::from(Value::Timestamp(secs)))
+        .map_err(|_| format!("negative timestamp: {secs}").into())
+}
+
+/// Converts the array a script passed to the named function into strings, failing on the first
+/// element that isn't a string, rather than skipping it
+fn strings(name: &str, items: rhai::Array) -> Result<Vec<String>, Box<rhai::EvalAltResult>> {
+    items
+        .into_iter()
+        .map(|item| {
+            item.into_string().map_err(|found| {
+                format!("{name} expects an array of strings, found {found}").into()
+            })
+        })
+        .collect()
+}
+
+/// Makes a [Value::Duration] from a number of seconds, for scripts
+fn duration(secs: rhai::INT) -> Result<Dynamic, Box<rhai::EvalAltResult>> {
+    u64::try_from(secs)
+        .map(|secs| Dynamic::from(Value::Duration(secs)))
+        .map_err(|_| format!("negative duration: {secs}").into())
+}
+
+/// Verifies an entry in one call: runs the unlock script against the proposed store, then tries
+/// each lock against the current store in order.
+///
+/// Returns the value of the first lock that succeeds, or the result of the last lock tried if
+/// none succeed.
+pub fn verify<C, P>(
+    current: C,
+    proposed: P,
+    unlock: &str,
+    locks: &[String],
+) -> Result<Option<Value>, ComradeError>
+where
+    C: Pairable + Send + Sync + 'static,
+    P: Pairable + Send + Sync + 'static,
+{
+    let unlocked =
+        ComradeBuilder::new(unlock, Current(current), Proposed(proposed)).try_unlock()?;
+
+    let mut result = None;
+    for lock in locks {
+        result = unlocked.try_lock(lock.as_str())?;
+        if let Some(Value::Success(_)) = result {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+/// Verifies an entry like [verify], but bounds the whole flow, the unlock script and every lock
+/// attempt, to the given time. Fails with [ComradeError::Timeout] if the time runs out, whether
+/// during a script or between them, without trying any further locks.
+pub fn verify_within<C, P>(
+    timeout: Duration,
+    current: C,
+    proposed: P,
+    unlock: &str,
+    locks: &[String],
+) -> Result<Option<Value>, ComradeError>
+where
+    C: Pairable + Send + Sync + 'static,
+    P: Pairable + Send + Sync + 'static,
+{
+    let deadline = Instant::now() + timeout;
+    let unlocked = ComradeBuilder::new(unlock, Current(current), Proposed(proposed))
+        .with_deadline(deadline)
+        .try_unlock()?;
+
+    let mut result = None;
+    for lock in locks {
+        if Instant::now() >= deadline {
+            return Err(ComradeError::Timeout);
+        }
+        result = unlocked.try_lock(lock.as_str())?;
+        if let Some(Value::Success(_)) = result {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+/// Parses the function names declared in a script's leading `// comrade: requires` comments
+fn requirements(script: &str) -> Vec<&str> {
+    script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map_while(|line| line.strip_prefix("//"))
+        .filter_map(|comment| comment.trim().strip_prefix("comrade:"))
+        .filter_map(|directive| directive.trim().strip_prefix("requires"))
+        .flat_map(|names| names.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
 #[cfg(test)]
 mod test_public_api {
     use super::*;
@@ -313,6 +1501,18 @@ mod test_public_api {
         }
     }
 
+    /// A current store holding "/answer" and a proposed store holding "/entry/", both "zig",
+    /// so an unlock script pushing "/entry/" satisfies a `check_eq("/answer")` lock
+    fn answer_stores() -> (ContextPairs, ContextPairs) {
+        let mut current = ContextPairs::default();
+        current.put("/answer", &"zig".into());
+
+        let mut proposed = ContextPairs::default();
+        proposed.put("/entry/", &"zig".into());
+
+        (current, proposed)
+    }
+
     fn unlock_script(entry_key: &str, proof_key: &str) -> String {
         let unlock_script = format!(
             r#"
@@ -398,4 +1598,1060 @@ mod test_public_api {
         assert_eq!(count, 1);
         Ok(())
     }
+
+    #[test]
+    fn test_verify() -> Result<(), Box<dyn std::error::Error>> {
+        let entry_key = "/entry/";
+
+        let entry_data = b"for great justice, move every zig!";
+        let proof_data = hex::decode("b92483a6c00600010040eda2eceac1ef60c4d54efc7b50d86b198ba12358749e5069dbe0a5ca6c3e7e78912a21c67a18a4a594f904e7df16f798d929d7a8cee57baca89b4ed0dfd1c801").unwrap();
+
+        let mut kvp_unlock = ContextPairs::default();
+        kvp_unlock.put(entry_key, &entry_data.to_vec().into());
+        kvp_unlock.put("/entry/proof", &proof_data.into());
+
+        let pub_key = hex::decode("ba24ed010874657374206b657901012069c9e8cd599542b5ff7e4cdc4265847feb9785330557edd6a9edae741ed4c3b2").unwrap();
+        let mut kvp_lock = ContextPairs::default();
+        kvp_lock.put("/pubkey", &pub_key.into());
+
+        let unlock = unlock_script(entry_key, &format!("{entry_key}proof"));
+        let locks = [first_lock_script(entry_key), other_lock_script(entry_key)];
+
+        let result = verify(kvp_lock, kvp_unlock, &unlock, &locks)?;
+
+        assert_eq!(result, Some(Value::Success(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_accessors() -> Result<(), Box<dyn std::error::Error>> {
+        let mut comrade = ComradeBuilder::new(
+            "true",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        )
+        .try_unlock()?;
+
+        let lock = r#"check_preimage("/hash")"#;
+        comrade.load(lock);
+        assert_eq!(comrade.script(), Some(lock));
+
+        comrade.clear_script();
+        assert_eq!(comrade.script(), None);
+        assert_eq!(comrade.run(), Err(ComradeError::NoScriptLoaded));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_lengths() -> Result<(), Box<dyn std::error::Error>> {
+        let mut current = ContextPairs::default();
+        current.put("/pubkey", &vec![1u8, 2, 3].into());
+
+        let mut proposed = ContextPairs::default();
+        proposed.put("/entry/", &"for great justice".into());
+        proposed.put("/entry/proof", &vec![4u8, 5, 6].into());
+        proposed.put("/entry/proof", &vec![7u8, 8, 9].into());
+
+        let unlocked =
+            ComradeBuilder::new("true", Current(current), Proposed(proposed)).try_unlock()?;
+
+        assert_eq!(unlocked.current_len(), 1);
+        assert_eq!(unlocked.proposed_len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_requirements() -> Result<(), Box<dyn std::error::Error>> {
+        let unlocked = ComradeBuilder::new(
+            "true",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        )
+        .try_unlock()?;
+
+        let supported = r#"
+            // comrade: requires check_signature, check_preimage
+            check_signature("/pubkey", "/entry/") || check_preimage("/hash")
+        "#;
+        assert_eq!(unlocked.check_requirements(supported), Ok(()));
+
+        let unsupported = r#"
+            // a threshold lock
+            // comrade: requires check_threshold_signature
+            check_threshold_signature("/threshold", "/entry/")
+        "#;
+        let err = unlocked.check_requirements(unsupported).unwrap_err();
+        assert_eq!(
+            err,
+            ComradeError::MissingFunction("check_threshold_signature".to_string())
+        );
+        assert!(err.to_string().contains("check_threshold_signature"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_return_stack_order() -> Result<(), Box<dyn std::error::Error>> {
+        let mut unlocked = ComradeBuilder::new(
+            "true",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        )
+        .try_unlock()?;
+
+        let lock = r#"
+            check_preimage("/first");
+            check_preimage("/second");
+            check_preimage("/third")
+        "#;
+        assert!(!unlocked.load(lock).run()?);
+
+        let returns: Vec<Value> = unlocked.returns().iter().cloned().collect();
+        assert_eq!(
+            returns,
+            vec![
+                Value::Failure("kvp missing key: /first".to_string()),
+                Value::Failure("kvp missing key: /second".to_string()),
+                Value::Failure("kvp missing key: /third".to_string()),
+            ]
+        );
+        assert_eq!(unlocked.returns().top(), returns.last().cloned());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_lock_counted() -> Result<(), Box<dyn std::error::Error>> {
+        let unlocked = ComradeBuilder::new(
+            "true",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        )
+        .try_unlock()?;
+
+        let (value, count) = unlocked.try_lock_counted(r#"check_preimage("/hash")"#)?;
+        assert!(matches!(value, Some(Value::Failure(_))));
+        assert_eq!(count, 1);
+
+        let (value, count) = unlocked.try_lock_counted(
+            r#"check_preimage("/hash") || check_preimage_raw("/digest", "sha2-256") || check_preimage("/other")"#,
+        )?;
+        assert!(matches!(value, Some(Value::Failure(_))));
+        assert_eq!(count, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_engine_config() -> Result<(), Box<dyn std::error::Error>> {
+        let mut unlocked = ComradeBuilder::new(
+            "true",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        )
+        .try_unlock()?;
+
+        unlocked.with_engine_config(|engine| {
+            engine.register_fn("double", |x: i32| x * 2);
+        });
+
+        assert!(unlocked.load("double(21) == 42").run()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_all() -> Result<(), Box<dyn std::error::Error>> {
+        let unlocked = ComradeBuilder::new(
+            "true",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        )
+        .try_unlock()?;
+
+        let valid = r#"check_signature("/pubkey", "/entry/") || check_preimage("/hash")"#;
+        let broken = r#"check_signature("/pubkey", "/entry/") ||"#;
+
+        assert_eq!(unlocked.compile_all(&[valid.to_string()]), Ok(()));
+
+        let errors = unlocked
+            .compile_all(&[valid.to_string(), broken.to_string()])
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 1);
+        assert!(matches!(errors[0].1, ComradeError::Compile(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_constant() -> Result<(), Box<dyn std::error::Error>> {
+        let mut current = ContextPairs::default();
+        current.put("/expected", &"zig".into());
+
+        let mut proposed = ContextPairs::default();
+        proposed.put("/answer", &"zig".into());
+
+        let unlocked =
+            ComradeBuilder::new("push(ANSWER_KEY)", Current(current), Proposed(proposed))
+                .with_constant("ANSWER_KEY", "/answer".into())
+                .with_constant("EXPECTED_KEY", "/expected".into())
+                .try_unlock()?;
+
+        assert_eq!(
+            unlocked.try_lock("check_eq(EXPECTED_KEY)")?,
+            Some(Value::Success(0))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_unlock_dry() -> Result<(), Box<dyn std::error::Error>> {
+        let benign = ComradeBuilder::new(
+            r#"push("/entry/"); push(branch("proof"));"#,
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        );
+        // the keys are missing, but the no-op functions don't care
+        assert_eq!(benign.try_unlock_dry(), Ok(()));
+
+        let broken = ComradeBuilder::new(
+            r#"push("/entry/"); check_signature("/pubkey", "/entry/")"#,
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        );
+        assert!(matches!(
+            broken.try_unlock_dry(),
+            Err(ComradeError::Eval(_))
+        ));
+
+        // a script that never ends is stopped by the deadline
+        let mut endless = ComradeBuilder::new(
+            "loop {}",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        );
+        endless.with_deadline(Instant::now() + std::time::Duration::from_millis(50));
+        assert_eq!(endless.try_unlock_dry(), Err(ComradeError::Timeout));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_either_forwards_pairs() {
+        let mut current = ContextPairs::default();
+        current.put("/pubkey", &"current".into());
+        let mut proposed = ContextPairs::default();
+        proposed.put("/pubkey", &"proposed".into());
+
+        let curr: Either<ContextPairs, ContextPairs> = Either::Curr(current);
+        let prop: Either<ContextPairs, ContextPairs> = Either::Prop(proposed);
+
+        assert_eq!(curr.get("/pubkey"), Some("current".into()));
+        assert_eq!(prop.get("/pubkey"), Some("proposed".into()));
+        assert_eq!(curr.get("/missing"), None);
+
+        let mut prop = prop;
+        prop.put("/entry/", &"zig".into());
+        assert_eq!(prop.len(), 2);
+        assert_eq!(curr.clone().len(), 1);
+    }
+
+    #[test]
+    fn test_from_initial_registers_lock() -> Result<(), Box<dyn std::error::Error>> {
+        let mut current = ContextPairs::default();
+        current.put("/answer", &"zig".into());
+
+        let mut context = Context::new(Either::Curr(current), ContextPairs::default());
+        context.pstack.push("zig".into());
+
+        // no explicit register_lock() call
+        let mut unlocked: Comrade<Unlocked, ContextPairs, ContextPairs> =
+            Comrade::new(context).into();
+
+        assert!(unlocked.load(r#"check_eq("/answer")"#).run()?);
+        assert_eq!(unlocked.returns().top(), Some(Value::Success(0)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_failure() -> Result<(), Box<dyn std::error::Error>> {
+        let context = Context::new(
+            Either::Curr(ContextPairs::default()),
+            ContextPairs::default(),
+        );
+        let mut unlocked: Comrade<Unlocked, ContextPairs, ContextPairs> =
+            Comrade::new(context).into();
+        assert_eq!(unlocked.last_failure(), None);
+
+        assert!(!unlocked.load(r#"check_eq("/answer")"#).run()?);
+        assert_eq!(
+            unlocked.last_failure(),
+            Some("kvp missing key: /answer".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlock_cannot_reach_current() -> Result<(), Box<dyn std::error::Error>> {
+        let mut current = ContextPairs::default();
+        current.put("/pubkey", &"zig".into());
+
+        let unlocked = ComradeBuilder::new(
+            r#"push("/pubkey")"#,
+            Current(current),
+            Proposed(ContextPairs::default()),
+        )
+        .try_unlock()?;
+
+        // the unlock script only sees the proposed store
+        assert_eq!(
+            unlocked.last_failure(),
+            Some("kvp missing key: /pubkey".to_string())
+        );
+
+        // and the current store is passed on unchanged
+        assert_eq!(unlocked.current_len(), 1);
+        let context = unlocked.context.lock();
+        assert_eq!(context.current.get("/pubkey"), Some("zig".into()));
+
+        Ok(())
+    }
+
+    /// A current store that records a key whenever "/touch" is read, standing in for a store
+    /// that changes as it is read
+    #[derive(Clone, Debug, Default)]
+    struct Touchy(Arc<Mutex<ContextPairs>>);
+
+    impl Pairs for Touchy {
+        fn get(&self, key: &str) -> Option<Value> {
+            let mut pairs = self.0.lock();
+            if key == "/touch" {
+                pairs.put("/touched", &"zig".into());
+                return Some("zig".into());
+            }
+            pairs.get(key)
+        }
+
+        fn put(&mut self, key: &str, value: &Value) -> Option<Value> {
+            self.0.lock().put(key, value)
+        }
+
+        fn len(&self) -> usize {
+            self.0.lock().len()
+        }
+
+        fn keys(&self) -> Option<Vec<String>> {
+            self.0.lock().keys()
+        }
+    }
+
+    #[test]
+    fn test_immutable_current() -> Result<(), Box<dyn std::error::Error>> {
+        let (_, proposed) = answer_stores();
+        let mut current = Touchy::default();
+        current.put("/answer", &"zig".into());
+
+        // a combined script that only reads the current store passes
+        ComradeBuilder::new(
+            r#"push("/entry/"); check_eq("/answer")"#,
+            Current(current.clone()),
+            Proposed(proposed.clone()),
+        )
+        .combined()
+        .with_immutable_current()
+        .try_unlock()?;
+
+        // as does a normal unlock, which never sees the current store
+        ComradeBuilder::new(
+            r#"push("/entry/")"#,
+            Current(current.clone()),
+            Proposed(proposed.clone()),
+        )
+        .with_immutable_current()
+        .try_unlock()?;
+
+        // reading "/touch" changes the store, which is caught
+        let script = r#"push("/entry/"); value_len("/touch"); true"#;
+        assert!(matches!(
+            ComradeBuilder::new(script, Current(current.clone()), Proposed(proposed.clone()))
+                .combined()
+                .with_immutable_current()
+                .try_unlock(),
+            Err(ComradeError::CurrentMutated)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_len_too_large() -> Result<(), Box<dyn std::error::Error>> {
+        // the fields share one buffer, so the map is over 2 GiB long without allocating it
+        let data: Arc<[u8]> = vec![0u8; 1 << 20].into();
+        let fields = (0..2048)
+            .map(|i| {
+                let value = Value::Bin {
+                    hint: String::new(),
+                    data: Arc::clone(&data),
+                };
+                (i.to_string(), value)
+            })
+            .collect();
+
+        let (mut current, proposed) = answer_stores();
+        current.put("/huge", &Value::Map(fields));
+        let unlocked =
+            ComradeBuilder::new(r#"push("/entry/")"#, Current(current), Proposed(proposed))
+                .with_shared_stores()
+                .try_unlock()?;
+
+        assert_eq!(
+            unlocked.try_lock(r#"if value_len("/answer") == 3 { check_eq("/answer") }"#)?,
+            Some(Value::Success(0))
+        );
+        assert!(matches!(
+            unlocked.try_lock(r#"value_len("/huge") > 0"#),
+            Err(ComradeError::Eval(e)) if e.contains("too large for value_len")
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_try_lock() -> Result<(), Box<dyn std::error::Error>> {
+        let mut unlocked = ComradeBuilder::new(
+            "true",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        )
+        .try_unlock()?;
+
+        // rendezvous() waits for both lock scripts to arrive, and records whether they met. If
+        // attempts were serialized, the first would give up waiting before the second started.
+        let arrivals = Arc::new((std::sync::Mutex::new(0), std::sync::Condvar::new()));
+        let met = Arc::new(Mutex::new(Vec::new()));
+        unlocked.with_engine_config({
+            let arrivals = Arc::clone(&arrivals);
+            let met = Arc::clone(&met);
+            move |engine| {
+                let arrivals = Arc::clone(&arrivals);
+                let met = Arc::clone(&met);
+                engine.register_fn("rendezvous", move || {
+                    let (count, arrived) = &*arrivals;
+                    let mut count = count.lock().unwrap();
+                    *count += 1;
+                    arrived.notify_all();
+                    let (count, _) = arrived
+                        .wait_timeout_while(count, std::time::Duration::from_secs(10), |n| *n < 2)
+                        .unwrap();
+                    met.lock().push(*count >= 2);
+                    true
+                });
+            }
+        });
+
+        std::thread::scope(|s| {
+            let a = s.spawn(|| unlocked.try_lock("rendezvous()"));
+            let b = s.spawn(|| unlocked.try_lock("rendezvous()"));
+            assert!(a.join().unwrap().is_ok());
+            assert!(b.join().unwrap().is_ok());
+        });
+        assert_eq!(*met.lock(), vec![true, true]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_lock_unlock_functions() -> Result<(), Box<dyn std::error::Error>> {
+        let mut current = ContextPairs::default();
+        current.put("/answer", &"zig".into());
+
+        let unlocked =
+            ComradeBuilder::new("true", Current(current), Proposed(ContextPairs::default()))
+                .try_unlock()?;
+
+        // lock scripts run on a fresh engine that still has push() and the domain functions
+        assert_eq!(
+            unlocked.try_lock(r#"push("/answer"); check_eq("/answer")"#)?,
+            Some(Value::Success(0))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pstack_after_unlock() -> Result<(), Box<dyn std::error::Error>> {
+        let mut proposed = ContextPairs::default();
+        proposed.put("/entry/", &"zig".into());
+        proposed.put("/entry/proof", &vec![1u8, 2, 3].into());
+
+        let unlocked = ComradeBuilder::new(
+            r#"push("/entry/"); push("/entry/proof")"#,
+            Current(ContextPairs::default()),
+            Proposed(proposed),
+        )
+        .try_unlock()?;
+
+        let pstack = unlocked.pstack();
+        assert_eq!(pstack.len(), 2);
+        assert_eq!(pstack.top(), Some(vec![1u8, 2, 3].into()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_store_setters() -> Result<(), Box<dyn std::error::Error>> {
+        let (current, proposed) = answer_stores();
+
+        let unlocked = ComradeBuilder::from_unlock(r#"push("/entry/")"#)
+            .with_current(Current(current))
+            .with_proposed(Proposed(proposed))
+            .try_unlock()?;
+
+        assert_eq!(unlocked.current_len(), 1);
+        assert_eq!(unlocked.proposed_len(), 1);
+        assert_eq!(
+            unlocked.try_lock(r#"check_eq("/answer")"#)?,
+            Some(Value::Success(0))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_registered_functions() {
+        let context = Context::new(
+            Either::Prop(ContextPairs::default()),
+            ContextPairs::default(),
+        );
+        let initial = Comrade::new(context);
+        let names = initial.registered_functions();
+        assert!(names.contains(&"push".to_string()));
+        assert!(!names.contains(&"check_signature".to_string()));
+
+        let unlocked: Comrade<Unlocked, ContextPairs, ContextPairs> = initial.into();
+        let unlocked_names = unlocked.registered_functions();
+        assert!(unlocked_names.contains(&"check_signature".to_string()));
+        assert_ne!(names, unlocked_names);
+    }
+
+    #[test]
+    fn test_get_field() -> Result<(), Box<dyn std::error::Error>> {
+        let mut current = ContextPairs::default();
+        current.put(
+            "/bundle",
+            &Value::Map(std::collections::HashMap::from([
+                ("alg".to_string(), Value::from("ed25519")),
+                ("sig".to_string(), Value::from(vec![1u8, 2, 3])),
+            ])),
+        );
+
+        let context = Context::new(Either::Curr(current), ContextPairs::default());
+        let mut unlocked: Comrade<Unlocked, ContextPairs, ContextPairs> =
+            Comrade::new(context).into();
+
+        assert!(unlocked
+            .load(r#"get_field("/bundle", "alg") == "ed25519""#)
+            .run()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_proposed() -> Result<(), Box<dyn std::error::Error>> {
+        let unlocked = ComradeBuilder::new(
+            "true",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        )
+        .try_unlock()?;
+        assert_eq!(unlocked.warnings(), vec!["the proposed store is empty"]);
+
+        // retrying the builder warns once per attempt, not once more for every earlier attempt
+        let mut builder = ComradeBuilder::new(
+            "true",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        );
+        builder.try_unlock()?;
+        assert_eq!(
+            builder.try_unlock()?.warnings(),
+            vec!["the proposed store is empty"]
+        );
+
+        let result = ComradeBuilder::new(
+            "true",
+            Current(ContextPairs::default()),
+            Proposed(ContextPairs::default()),
+        )
+        .with_required_proposed()
+        .try_unlock();
+        assert!(matches!(result, Err(ComradeError::EmptyProposed)));
+
+        let mut proposed = ContextPairs::default();
+        proposed.put("/entry/", &"zig".into());
+        let unlocked =
+            ComradeBuilder::new("true", Current(ContextPairs::default()), Proposed(proposed))
+                .with_required_proposed()
+                .try_unlock()?;
+        assert!(unlocked.warnings().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cond() -> Result<(), Box<dyn std::error::Error>> {
+        let mut current = ContextPairs::default();
+        current.put("/answer", &"zig".into());
+        current.put("/zig_proof", &"for great justice".into());
+        current.put("/zag_proof", &"move every zig".into());
+        current.put("/expected", &"for great justice".into());
+
+        let lock = r#"
+            cond(check_eq("/answer"), "/zig_proof", "/zag_proof");
+            check_eq("/expected")
+        "#;
+
+        for (answer, expected) in [("zig", true), ("zag", false)] {
+            let mut proposed = ContextPairs::default();
+            proposed.put("/entry/", &answer.into());
+
+            let unlocked = ComradeBuilder::new(
+                r#"push("/entry/")"#,
+                Current(current.clone()),
+                Proposed(proposed),
+            )
+            .try_unlock()?;
+
+            let result = unlocked.try_lock(lock)?;
+            assert_eq!(matches!(result, Some(Value::Success(_))), expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_into_next_current() -> Result<(), Box<dyn std::error::Error>> {
+        // the first entry proposes the answer the second entry is checked against
+        let mut first = ContextPairs::default();
+        first.put("/answer", &"zig".into());
+        first.put("/entry/", &"zig".into());
+
+        let unlocked = ComradeBuilder::new(
+            r#"push("/entry/")"#,
+            Current(ContextPairs::default()),
+            Proposed(first),
+        )
+        .try_unlock()?;
+        let next = unlocked.into_next_current();
+        assert_eq!(next.get("/answer"), Some("zig".into()));
+
+        let mut second = ContextPairs::default();
+        second.put("/entry/", &"zig".into());
+
+        let unlocked =
+            ComradeBuilder::new(r#"push("/entry/")"#, next, Proposed(second)).try_unlock()?;
+        assert_eq!(
+            unlocked.try_lock(r#"check_eq("/answer")"#)?,
+            Some(Value::Success(0))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pstack_eq() -> Result<(), Box<dyn std::error::Error>> {
+        let mut proposed = ContextPairs::default();
+        proposed.put("/entry/", &"zig".into());
+        proposed.put("/entry/proof", &vec![1u8, 2, 3].into());
+
+        let unlocked = ComradeBuilder::new(
+            r#"push("/entry/"); push("/entry/proof"); push("/entry/")"#,
+            Current(ContextPairs::default()),
+            Proposed(proposed),
+        )
+        .try_unlock()?;
+
+        assert_eq!(
+            unlocked.pstack(),
+            Stk::from(vec!["zig".into(), vec![1u8, 2, 3].into(), "zig".into()])
+        );
+        assert_ne!(
+            unlocked.pstack(),
+            Stk::from(vec![vec![1u8, 2, 3].into(), "zig".into(), "zig".into()])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stores_from_maps() -> Result<(), Box<dyn std::error::Error>> {
+        use std::collections::HashMap;
+
+        let current = HashMap::from([("/answer".to_string(), Value::from("zig"))]);
+        let proposed = HashMap::from([("/entry/".to_string(), Value::from("zig"))]);
+
+        let unlocked = ComradeBuilder::new(
+            r#"push("/entry/")"#,
+            Current::<ContextPairs>::from(current.clone()),
+            Proposed::<ContextPairs>::from(proposed),
+        )
+        .try_unlock()?;
+
+        assert_eq!(
+            unlocked.try_lock(r#"check_eq("/answer")"#)?,
+            Some(Value::Success(0))
+        );
+        assert_eq!(
+            Current::<ContextPairs>::from(current.clone()).into_inner(),
+            ContextPairs::from(current)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_unlocked() -> Result<(), Box<dyn std::error::Error>> {
+        let (current, proposed) = answer_stores();
+
+        let unlocked =
+            ComradeBuilder::new(r#"push("/entry/")"#, Current(current), Proposed(proposed))
+                .try_unlock()?;
+
+        let mut cloned = unlocked.clone();
+        assert_eq!(
+            cloned.registered_functions(),
+            unlocked.registered_functions()
+        );
+
+        // running a lock on the clone leaves the original untouched
+        cloned.load(r#"check_eq("/answer")"#).run()?;
+        assert_eq!(cloned.context.lock().rstack.top(), Some(Value::Success(0)));
+        assert_eq!(unlocked.context.lock().rstack.top(), None);
+        assert_eq!(unlocked.pstack().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_print_handler() -> Result<(), Box<dyn std::error::Error>> {
+        let printed = Arc::new(Mutex::new(Vec::new()));
+
+        let (current, proposed) = answer_stores();
+
+        let unlocked = ComradeBuilder::new(
+            r#"print("unlocking"); push("/entry/")"#,
+            Current(current),
+            Proposed(proposed),
+        )
+        .with_print_handler({
+            let printed = Arc::clone(&printed);
+            move |msg| printed.lock().push(msg.to_string())
+        })
+        .try_unlock()?;
+
+        assert_eq!(
+            unlocked.try_lock(r#"print("locking"); check_eq("/answer")"#)?,
+            Some(Value::Success(0))
+        );
+        assert_eq!(*printed.lock(), vec!["unlocking", "locking"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_lock_outcome() -> Result<(), Box<dyn std::error::Error>> {
+        let (current, proposed) = answer_stores();
+
+        let unlocked =
+            ComradeBuilder::new(r#"push("/entry/")"#, Current(current), Proposed(proposed))
+                .try_unlock()?;
+
+        assert_eq!(
+            unlocked.try_lock_outcome(r#"check_eq("/missing") || check_eq("/answer")"#)?,
+            VerifyOutcome {
+                success: true,
+                check_count: 2,
+                failure_reason: None,
+            }
+        );
+        assert_eq!(
+            unlocked.try_lock_outcome(r#"check_eq("/missing")"#)?,
+            VerifyOutcome {
+                success: false,
+                check_count: 1,
+                failure_reason: Some("kvp missing key: /missing".to_string()),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_unlock_rolls_back() -> Result<(), Box<dyn std::error::Error>> {
+        let mut proposed = ContextPairs::default();
+        proposed.put("/entry/", &"zig".into());
+
+        let mut builder = ComradeBuilder::new(
+            r#"push("/entry/"); push_domain("/forks/"); throw "midway";"#,
+            Current(ContextPairs::default()),
+            Proposed(proposed.clone()),
+        );
+
+        // the first attempt's pushes are discarded, and a retry runs the same script again
+        for _ in 0..2 {
+            assert!(
+                matches!(builder.try_unlock(), Err(ComradeError::Eval(e)) if e.contains("midway"))
+            );
+
+            let context = builder.context.lock();
+            assert_eq!(*context.proposed, proposed);
+            assert!(context.pstack.is_empty());
+            assert_eq!(context.domain, "/");
+        }
+
+        // a successful unlock shares the stores rather than copying them
+        builder.unlock_script = r#"push("/entry/")"#.to_string();
+        let unlocked = builder.try_unlock()?;
+        assert!(Arc::ptr_eq(
+            &builder.context.lock().proposed,
+            &unlocked.context.lock().proposed
+        ));
+
+        // and putting into the builder afterwards leaves the unlocked stores alone
+        builder.put("/entry/", "zag".into());
+        assert_eq!(
+            unlocked.context.lock().proposed.get("/entry/"),
+            Some("zig".into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_reports() -> Result<(), Box<dyn std::error::Error>> {
+        let (current, proposed) = answer_stores();
+
+        let unlocked = ComradeBuilder::new(
+            r#"let pushed = push("/entry/"); report("pushed", pushed); pushed"#,
+            Current(current),
+            Proposed(proposed),
+        )
+        .try_unlock()?;
+
+        let mut lock = unlocked.clone();
+        lock.load(
+            r#"
+            let step1 = check_eq("/missing");
+            report("step1", step1);
+            let step2 = check_eq("/answer");
+            report("step2", step2);
+            report("answer", get("/answer"));
+            step1 || step2
+            "#,
+        )
+        .run()?;
+
+        assert_eq!(
+            lock.script_reports(),
+            vec![
+                ("pushed".to_string(), Value::from("true")),
+                ("step1".to_string(), Value::from("false")),
+                ("step2".to_string(), Value::from("true")),
+                ("answer".to_string(), Value::from("zig")),
+            ]
+        );
+        assert_eq!(unlocked.script_reports().len(), 1);
+
+        // time values made in a script keep their type
+        let mut lock = unlocked.clone();
+        lock.load(r#"report("issued", timestamp(1000)); report("ttl", duration(60)); true"#)
+            .run()?;
+        assert_eq!(
+            lock.script_reports()[1..],
+            [
+                ("issued".to_string(), Value::Timestamp(1_000)),
+                ("ttl".to_string(), Value::Duration(60)),
+            ]
+        );
+        assert!(lock.load("duration(-1)").run().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_domain_order() -> Result<(), Box<dyn std::error::Error>> {
+        let mut proposed = ContextPairs::default();
+        proposed.put("/entry/", &"zig".into());
+        let proposed = Proposed(proposed);
+
+        let mut before = ComradeBuilder::from_unlock(r#"push("/entry/")"#);
+        before
+            .with_domain("/forks/child/")
+            .with_proposed(proposed.clone())
+            .with_current(Current(ContextPairs::default()))
+            .with_max_domain_depth(4);
+
+        let mut after = ComradeBuilder::from_unlock(r#"push("/entry/")"#);
+        after
+            .with_max_domain_depth(4)
+            .with_current(Current(ContextPairs::default()))
+            .with_proposed(proposed)
+            .with_domain("/forks/child/");
+
+        for builder in [&mut before, &mut after] {
+            assert_eq!(builder.domain(), "/forks/child/");
+            let unlocked = builder.try_unlock()?;
+            assert_eq!(unlocked.context.lock().domain, "/forks/child/");
+        }
+        assert_eq!(
+            ComradeBuilder::<ContextPairs, ContextPairs>::from_unlock("true").domain(),
+            "/"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_within() -> Result<(), Box<dyn std::error::Error>> {
+        let (current, proposed) = answer_stores();
+
+        let slow = "let x = 0; loop { x += 1; }".to_string();
+        let fast = r#"check_eq("/answer")"#.to_string();
+
+        // the slow lock never finishes, so the deadline stops it and the locks after it
+        let start = Instant::now();
+        assert_eq!(
+            verify_within(
+                Duration::from_millis(50),
+                current.clone(),
+                proposed.clone(),
+                r#"push("/entry/")"#,
+                &[slow, fast.clone()],
+            ),
+            Err(ComradeError::Timeout)
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert_eq!(
+            verify_within(
+                Duration::from_secs(5),
+                current,
+                proposed,
+                r#"push("/entry/")"#,
+                &[fast],
+            )?,
+            Some(Value::Success(0))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_clock() -> Result<(), Box<dyn std::error::Error>> {
+        let mut current = ContextPairs::default();
+        current.put("/expires", &Value::Timestamp(2_000));
+
+        let mut proposed = ContextPairs::default();
+        proposed.put("/entry/", &"zig".into());
+
+        // a mock clock the test moves forward
+        let now = Arc::new(std::sync::atomic::AtomicU64::new(1_999));
+        let unlocked = ComradeBuilder::new("true", Current(current), Proposed(proposed))
+            .with_clock({
+                let now = Arc::clone(&now);
+                Arc::new(move || now.load(std::sync::atomic::Ordering::SeqCst))
+            })
+            .try_unlock()?;
+
+        assert_eq!(
+            unlocked.try_lock(r#"check_before("/expires")"#)?,
+            Some(Value::Success(0))
+        );
+        assert!(matches!(
+            unlocked.try_lock(r#"check_after("/expires")"#)?,
+            Some(Value::Failure(_))
+        ));
+
+        now.store(2_001, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(
+            unlocked.try_lock(r#"check_after("/expires")"#)?,
+            Some(Value::Success(0))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_silent() -> Result<(), Box<dyn std::error::Error>> {
+        let printed = Arc::new(Mutex::new(Vec::new()));
+
+        let (current, proposed) = answer_stores();
+
+        let unlocked = ComradeBuilder::new(
+            r#"print("unlocking"); debug("unlocking"); push("/entry/")"#,
+            Current(current),
+            Proposed(proposed),
+        )
+        .with_print_handler({
+            let printed = Arc::clone(&printed);
+            move |msg| printed.lock().push(msg.to_string())
+        })
+        .with_silent()
+        .try_unlock()?;
+
+        assert_eq!(
+            unlocked.try_lock(r#"print("locking"); check_eq("/answer")"#)?,
+            Some(Value::Success(0))
+        );
+        assert!(printed.lock().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump() -> Result<(), Box<dyn std::error::Error>> {
+        let mut current = ContextPairs::default();
+        current.put("/pubkey", &vec![0xde, 0xad, 0xbe, 0xef].into());
+        current.put("/name", &"zig".into());
+
+        let mut proposed = ContextPairs::default();
+        proposed.put("/entry/", &vec![0xca, 0xfe].into());
+
+        let mut builder =
+            ComradeBuilder::new(r#"push("/entry/")"#, Current(current), Proposed(proposed));
+
+        let dump = builder.try_unlock()?.dump();
+        let keys = |pairs: &Option<Vec<(String, String)>>| {
+            pairs
+                .iter()
+                .flatten()
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(&dump.current), ["/name", "/pubkey"]);
+        assert_eq!(keys(&dump.proposed), ["/entry/"]);
+        assert_eq!(dump.pstack, ["bin(2 bytes) <redacted>"]);
+        assert_eq!(dump.domain, "/");
+        assert_eq!(dump.check_count, 0);
+
+        let text = dump.to_string();
+        assert!(text.contains("/pubkey = bin(4 bytes) <redacted>"));
+        assert!(text.contains("/name = str(3 bytes) <redacted>"));
+        assert!(!text.contains("deadbeef"));
+        assert!(!text.contains("zig"));
+
+        let dump = builder.with_redaction(false).try_unlock()?.dump();
+        let text = dump.to_string();
+        assert!(text.contains("/pubkey = bin(4 bytes) deadbeef"));
+        assert!(text.contains(r#"/name = str(3 bytes) "zig""#));
+        assert_eq!(dump.pstack, ["bin(2 bytes) cafe"]);
+
+        Ok(())
+    }
 }
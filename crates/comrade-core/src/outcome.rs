@@ -0,0 +1,33 @@
+//! Outcome module
+use crate::Value;
+
+/// A lock result flattened into plain fields, for callers such as FFI bindings that can't
+/// easily marshal an `Option<Value>`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyOutcome {
+    /// Whether the lock succeeded
+    pub success: bool,
+    /// The number of check_* operations that ran during the attempt
+    pub check_count: usize,
+    /// Why the lock failed, if it did
+    pub failure_reason: Option<String>,
+}
+
+impl VerifyOutcome {
+    /// Flattens the top of the return stack left by a lock attempt, and the number of checks
+    /// it ran. A lock that evaluated no check fails with a reason saying so.
+    pub fn new(result: Option<Value>, check_count: usize) -> Self {
+        let failure_reason = match result {
+            Some(Value::Success(_)) => None,
+            Some(Value::Failure(reason)) => Some(reason),
+            Some(value) => Some(format!("unexpected value on return stack: {value:?}")),
+            None => Some("no check was evaluated".to_string()),
+        };
+
+        VerifyOutcome {
+            success: failure_reason.is_none(),
+            check_count,
+            failure_reason,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::storage::value::{encode_bytes, encode_u64};
 use crate::Value;
 use std::fmt::Debug;
 
@@ -9,4 +10,174 @@ pub trait Pairs: Debug {
     /// add a key-value pair to the storage, returns the previous value if the
     /// key already exists in the data structure
     fn put(&mut self, key: &str, value: &Value) -> Option<Value>;
+
+    /// add a key-value pair to the storage only if the value associated with the key matches
+    /// `expected`, where `None` means the key must not exist yet. Returns whether the value
+    /// was put.
+    fn compare_and_put(&mut self, key: &str, expected: Option<&Value>, new: &Value) -> bool {
+        if self.get(key).as_ref() != expected {
+            return false;
+        }
+        self.put(key, new);
+        true
+    }
+
+    /// return the number of key-value pairs in the storage
+    fn len(&self) -> usize;
+
+    /// return if the storage is empty
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// return the keys in the storage, in arbitrary order, or `None` if the storage can't list
+    /// them. Used by [crate::Comrade::dump]. Defaults to `None`.
+    fn keys(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// return a canonical encoding of the storage for signing, leaving out the keys in `skip`,
+    /// or `None` if the storage can't list its keys. The encoding is the number of pairs as a
+    /// big-endian `u64`, then each pair sorted by key, as the length-prefixed key followed by
+    /// the [Value::canonical_bytes] of its value.
+    fn canonical_bytes(&self, skip: &[&str]) -> Option<Vec<u8>> {
+        let mut keys = self.keys()?;
+        keys.retain(|key| !skip.contains(&key.as_str()));
+        keys.sort();
+
+        let mut out = Vec::new();
+        encode_u64(keys.len() as u64, &mut out);
+        for key in keys {
+            let value = self.get(&key)?;
+            encode_bytes(key.as_bytes(), &mut out);
+            value.encode_canonical(&mut out);
+        }
+        Some(out)
+    }
+
+    /// return a boxed copy of the storage, if it can be copied. Used by [BoxedPairs::try_clone].
+    /// Defaults to `None`.
+    fn clone_box(&self) -> Option<Box<dyn Pairs + Send + Sync>> {
+        None
+    }
+}
+
+/// A [Pairs] trait object, for holding different kinds of stores side by side, such as stores
+/// from plugins. Every call is forwarded to the inner store.
+///
+/// A [BoxedPairs] is not [Clone] or [Default], so it isn't [crate::Pairable] and can't be used as
+/// a [crate::Comrade] store directly. It can be copied with [BoxedPairs::try_clone] when the
+/// inner store implements [Pairs::clone_box].
+#[derive(Debug)]
+pub struct BoxedPairs(pub Box<dyn Pairs + Send + Sync>);
+
+impl BoxedPairs {
+    /// Boxes the given store
+    pub fn new(pairs: impl Pairs + Send + Sync + 'static) -> Self {
+        BoxedPairs(Box::new(pairs))
+    }
+
+    /// Copies the store, or returns `None` if the inner store can't be copied
+    pub fn try_clone(&self) -> Option<Self> {
+        self.0.clone_box().map(BoxedPairs)
+    }
+}
+
+impl Pairs for BoxedPairs {
+    fn get(&self, key: &str) -> Option<Value> {
+        self.0.get(key)
+    }
+
+    fn put(&mut self, key: &str, value: &Value) -> Option<Value> {
+        self.0.put(key, value)
+    }
+
+    fn compare_and_put(&mut self, key: &str, expected: Option<&Value>, new: &Value) -> bool {
+        self.0.compare_and_put(key, expected, new)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn keys(&self) -> Option<Vec<String>> {
+        self.0.keys()
+    }
+
+    fn clone_box(&self) -> Option<Box<dyn Pairs + Send + Sync>> {
+        self.0.clone_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ContextPairs;
+
+    /// A read-only store holding a single key-value pair
+    #[derive(Debug)]
+    struct Single(&'static str, &'static str);
+
+    impl Pairs for Single {
+        fn get(&self, key: &str) -> Option<Value> {
+            (key == self.0).then(|| Value::from(self.1))
+        }
+
+        fn put(&mut self, _key: &str, _value: &Value) -> Option<Value> {
+            None
+        }
+
+        fn len(&self) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn test_boxed_pairs() {
+        let mut pairs = ContextPairs::default();
+        pairs.put("/a", &"zig".into());
+
+        let stores = [BoxedPairs::new(pairs), BoxedPairs::new(Single("/b", "zag"))];
+
+        assert_eq!(stores[0].get("/a"), Some(Value::from("zig")));
+        assert_eq!(stores[0].get("/b"), None);
+        assert_eq!(stores[1].get("/b"), Some(Value::from("zag")));
+        assert_eq!(stores[1].len(), 1);
+
+        // only stores that implement clone_box can be copied
+        let copy = stores[0].try_clone().expect("ContextPairs can be copied");
+        assert_eq!(copy.get("/a"), Some(Value::from("zig")));
+        assert!(stores[1].try_clone().is_none());
+    }
+
+    #[test]
+    fn test_canonical_bytes() {
+        let mut a = ContextPairs::default();
+        a.put("/a", &"zig".into());
+        a.put("/b", &vec![1u8, 2].into());
+
+        // insertion order and hints don't change the encoding
+        let mut b = ContextPairs::default();
+        b.put(
+            "/b",
+            &Value::Bin {
+                hint: "proof".to_string(),
+                data: vec![1u8, 2].into(),
+            },
+        );
+        b.put("/a", &"zig".into());
+        assert_eq!(a.canonical_bytes(&[]), b.canonical_bytes(&[]));
+
+        // skipped keys are left out, and changed values change the encoding
+        b.put("/c", &"zag".into());
+        assert_ne!(a.canonical_bytes(&[]), b.canonical_bytes(&[]));
+        assert_eq!(a.canonical_bytes(&[]), b.canonical_bytes(&["/c"]));
+
+        // a store that can't list its keys has no encoding
+        assert_eq!(Single("/a", "zig").canonical_bytes(&[]), None);
+    }
 }
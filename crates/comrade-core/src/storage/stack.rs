@@ -19,13 +19,51 @@ pub trait Stack {
 
     /// return if the stack is empty
     fn is_empty(&self) -> bool;
+
+    /// reserve space for at least `additional` more values. This is only a hint: by default it
+    /// does nothing, for stacks that can't reserve space, and [Stk] implements it.
+    fn reserve(&mut self, _additional: usize) {}
 }
 
-#[derive(Default, Clone, Debug)]
+/// A [Vec] backed [Stack]. Values are stored bottom-to-top, so the last pushed value is the top.
+/// Two stacks are equal when they hold equal values in the same order.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct Stk {
     pub stack: Vec<Value>,
 }
 
+impl Stk {
+    /// Create an empty stack with space for at least `capacity` values, so a reused stack
+    /// doesn't reallocate as it grows
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            stack: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// The number of values the stack can hold without reallocating
+    pub fn capacity(&self) -> usize {
+        self.stack.capacity()
+    }
+
+    /// Release any capacity beyond the values currently on the stack
+    pub fn shrink_to_fit(&mut self) {
+        self.stack.shrink_to_fit();
+    }
+
+    /// Iterate over the values from bottom to top, in the order they were pushed
+    pub fn iter(&self) -> std::slice::Iter<'_, Value> {
+        self.stack.iter()
+    }
+}
+
+/// Builds a stack from values ordered bottom-to-top, so the last value is the top
+impl From<Vec<Value>> for Stk {
+    fn from(stack: Vec<Value>) -> Self {
+        Self { stack }
+    }
+}
+
 impl Stack for Stk {
     /// push a value onto the stack
     fn push(&mut self, value: Value) {
@@ -59,4 +97,36 @@ impl Stack for Stk {
     fn is_empty(&self) -> bool {
         self.stack.is_empty()
     }
+
+    /// reserve space for at least `additional` more values
+    fn reserve(&mut self, additional: usize) {
+        self.stack.reserve(additional);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserved_capacity() {
+        let mut stk = Stk::with_capacity(8);
+        let capacity = stk.capacity();
+        assert!(capacity >= 8);
+
+        // reusing the stack within its reservation never grows it
+        for _ in 0..100 {
+            for i in 0..8 {
+                stk.push(Value::Success(i));
+            }
+            while stk.pop().is_some() {}
+        }
+        assert_eq!(stk.capacity(), capacity);
+
+        stk.shrink_to_fit();
+        assert!(stk.capacity() < capacity);
+
+        stk.reserve(4);
+        assert!(stk.capacity() >= 4);
+    }
 }
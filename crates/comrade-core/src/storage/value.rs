@@ -1,12 +1,24 @@
+use crate::ComradeError;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
 /// The values that can be pushed onto the stack
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Value {
     /// A binary blob value with debugging hint
     Bin {
         /// Arbitrary description of the data for debugging purposes
         hint: String,
-        /// Binary value data
-        data: Vec<u8>,
+        /// Binary value data, reference counted so that cloning a value doesn't copy it.
+        ///
+        /// This was a `Vec<u8>`, so code that builds or destructures [Value::Bin] directly
+        /// must change: build values with `Value::from(bytes)`, and copy the data out with
+        /// `data.to_vec()` where a `Vec<u8>` is needed.
+        data: Arc<[u8]>,
     },
     /// A printable string value with debugging hint
     Str {
@@ -15,20 +27,191 @@ pub enum Value {
         /// String value data
         data: String,
     },
+    /// A structured value of named fields, such as a signature bundle with metadata
+    Map(HashMap<String, Value>),
+    /// A point in time, in seconds since the unix epoch
+    Timestamp(u64),
+    /// A span of time, in seconds
+    Duration(u64),
     /// Sucess marker
     Success(usize),
     /// Failure marker
     Failure(String),
 }
 
+impl Value {
+    /// Compares only the payload of two values, ignoring any `hint`. A [Value::Str] and a
+    /// [Value::Bin] are equal when they hold the same bytes. The derived [PartialEq] remains
+    /// strict and compares hints too.
+    pub fn content_eq(&self, other: &Value) -> bool {
+        match (self.payload(), other.payload()) {
+            (Some(a), Some(b)) => a == b,
+            _ => self == other,
+        }
+    }
+
+    /// Decode a hex string into a [Value::Bin]
+    pub fn from_hex(s: &str) -> Result<Value, ComradeError> {
+        hex::decode(s)
+            .map(Value::from)
+            .map_err(|e| ComradeError::Decode(e.to_string()))
+    }
+
+    /// Decode a standard, padded base64 string into a [Value::Bin]
+    pub fn from_base64(s: &str) -> Result<Value, ComradeError> {
+        STANDARD
+            .decode(s)
+            .map(Value::from)
+            .map_err(|e| ComradeError::Decode(e.to_string()))
+    }
+
+    /// The byte length of a [Value::Bin] or [Value::Str] payload. The length of a [Value::Map]
+    /// is the sum of its field name and value lengths, so size limits apply to maps too.
+    /// Markers have no payload, so their length is zero.
+    pub fn len(&self) -> usize {
+        match self {
+            Value::Map(fields) => fields
+                .iter()
+                .map(|(name, value)| name.len().saturating_add(value.len()))
+                .fold(0, usize::saturating_add),
+            _ => self.payload().map_or(0, <[u8]>::len),
+        }
+    }
+
+    /// Whether the value has an empty (or no) payload
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A canonical encoding of the value, for signing. Hints are left out, and map fields are
+    /// sorted by name, so equal payloads always encode to the same bytes. Each value is a tag
+    /// byte followed by its data, with lengths and numbers as big-endian `u64`s:
+    ///
+    /// - [Value::Bin] `0x00`, length, data
+    /// - [Value::Str] `0x01`, length, UTF-8 bytes
+    /// - [Value::Map] `0x02`, field count, then each field's length-prefixed name and value
+    /// - [Value::Timestamp] `0x03`, seconds
+    /// - [Value::Duration] `0x04`, seconds
+    /// - [Value::Success] `0x05`, count
+    /// - [Value::Failure] `0x06`, length, UTF-8 bytes
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_canonical(&mut out);
+        out
+    }
+
+    /// Appends the [Value::canonical_bytes] of the value to `out`
+    pub(crate) fn encode_canonical(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Bin { hint: _, data } => {
+                out.push(0x00);
+                encode_bytes(data, out);
+            }
+            Value::Str { hint: _, data } => {
+                out.push(0x01);
+                encode_bytes(data.as_bytes(), out);
+            }
+            Value::Map(fields) => {
+                out.push(0x02);
+                encode_u64(fields.len() as u64, out);
+                let mut fields = fields.iter().collect::<Vec<_>>();
+                fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+                for (name, value) in fields {
+                    encode_bytes(name.as_bytes(), out);
+                    value.encode_canonical(out);
+                }
+            }
+            Value::Timestamp(secs) => {
+                out.push(0x03);
+                encode_u64(*secs, out);
+            }
+            Value::Duration(secs) => {
+                out.push(0x04);
+                encode_u64(*secs, out);
+            }
+            Value::Success(count) => {
+                out.push(0x05);
+                encode_u64(*count as u64, out);
+            }
+            Value::Failure(reason) => {
+                out.push(0x06);
+                encode_bytes(reason.as_bytes(), out);
+            }
+        }
+    }
+
+    /// The payload bytes of a [Value::Bin] or [Value::Str]
+    fn payload(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bin { hint: _, data } => Some(&data[..]),
+            Value::Str { hint: _, data } => Some(data.as_bytes()),
+            _ => None,
+        }
+    }
+}
+
+/// Appends the number as a big-endian `u64`, for [Value::canonical_bytes]
+pub(crate) fn encode_u64(n: u64, out: &mut Vec<u8>) {
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+/// Appends the bytes prefixed with their length, for [Value::canonical_bytes]
+pub(crate) fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    encode_u64(bytes.len() as u64, out);
+    out.extend_from_slice(bytes);
+}
+
+/// Hashes consistently with [PartialEq]. [Value::Map] fields are hashed independently of
+/// their iteration order.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Bin { hint, data } => {
+                hint.hash(state);
+                data.hash(state);
+            }
+            Value::Str { hint, data } => {
+                hint.hash(state);
+                data.hash(state);
+            }
+            Value::Map(fields) => {
+                fields.len().hash(state);
+                unordered_hash(fields.iter()).hash(state);
+            }
+            Value::Timestamp(secs) | Value::Duration(secs) => secs.hash(state),
+            Value::Success(count) => count.hash(state),
+            Value::Failure(err) => err.hash(state),
+        }
+    }
+}
+
+/// Hashes each key-value pair on its own and sums the hashes, so the result doesn't depend on
+/// the order a map happens to iterate in
+pub(crate) fn unordered_hash<'a>(pairs: impl Iterator<Item = (&'a String, &'a Value)>) -> u64 {
+    pairs
+        .map(|pair| {
+            let mut hasher = DefaultHasher::new();
+            pair.hash(&mut hasher);
+            hasher.finish()
+        })
+        .fold(0, u64::wrapping_add)
+}
+
 impl From<&[u8]> for Value {
     fn from(b: &[u8]) -> Self {
-        Value::from(b.to_vec())
+        Value::from(Arc::<[u8]>::from(b))
     }
 }
 
 impl From<Vec<u8>> for Value {
     fn from(b: Vec<u8>) -> Self {
+        Value::from(Arc::<[u8]>::from(b))
+    }
+}
+
+impl From<Arc<[u8]>> for Value {
+    fn from(b: Arc<[u8]>) -> Self {
         Value::Bin {
             hint: "".to_string(),
             data: b,
@@ -56,3 +239,191 @@ impl From<usize> for Value {
         Value::Success(n)
     }
 }
+
+/// Converts a [Value] into a Rhai [rhai::Dynamic], so it can be used from scripts.
+/// Binary data becomes a blob, maps become object maps, and markers become their count or
+/// error message. Success counts too large for a Rhai integer saturate at its maximum.
+/// Timestamps and durations become their number of seconds, or its decimal string if it
+/// doesn't fit in a Rhai integer.
+impl From<Value> for rhai::Dynamic {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Bin { hint: _, data } => rhai::Dynamic::from_blob(data.to_vec()),
+            Value::Str { hint: _, data } => data.into(),
+            Value::Map(fields) => rhai::Dynamic::from_map(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| (name.into(), value.into()))
+                    .collect(),
+            ),
+            Value::Timestamp(secs) | Value::Duration(secs) => {
+                rhai::INT::try_from(secs).map_or_else(|_| secs.to_string().into(), Into::into)
+            }
+            Value::Success(count) => rhai::INT::try_from(count).unwrap_or(rhai::INT::MAX).into(),
+            Value::Failure(err) => err.into(),
+        }
+    }
+}
+
+/// Converts a Rhai [rhai::Dynamic] from a script into a [Value]. Blobs become binary data
+/// and object maps become maps. A [Value] carried by the dynamic, such as one made with the
+/// timestamp() or duration() script functions, is returned as it is. Anything else, such as a
+/// string, number or bool, becomes its printed form.
+impl From<rhai::Dynamic> for Value {
+    fn from(value: rhai::Dynamic) -> Self {
+        if value.is::<Value>() {
+            return value.cast::<Value>();
+        }
+        if value.is_blob() {
+            return Value::from(value.cast::<rhai::Blob>());
+        }
+        if value.is_map() {
+            return Value::Map(
+                value
+                    .cast::<rhai::Map>()
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value.into()))
+                    .collect(),
+            );
+        }
+        Value::from(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_eq_ignores_hint() {
+        let a = Value::Bin {
+            hint: "a".to_string(),
+            data: vec![1, 2, 3].into(),
+        };
+        let b = Value::Bin {
+            hint: "b".to_string(),
+            data: vec![1, 2, 3].into(),
+        };
+
+        assert_ne!(a, b);
+        assert!(a.content_eq(&b));
+        assert!(!a.content_eq(&Value::from(vec![1u8, 2])));
+    }
+
+    #[test]
+    fn test_content_eq_across_types() {
+        let s = Value::from("zig");
+        let b = Value::from(b"zig".to_vec());
+
+        assert_ne!(s, b);
+        assert!(s.content_eq(&b));
+        assert!(b.content_eq(&s));
+        assert!(!s.content_eq(&Value::Success(0)));
+    }
+
+    #[test]
+    fn test_len() {
+        let bin = Value::from(vec![1u8, 2, 3]);
+        assert_eq!(bin.len(), 3);
+        assert!(!bin.is_empty());
+
+        let empty = Value::from(Vec::<u8>::new());
+        assert_eq!(empty.len(), 0);
+        assert!(empty.is_empty());
+
+        assert_eq!(Value::from("zig").len(), 3);
+        assert!(Value::Success(0).is_empty());
+    }
+
+    #[test]
+    fn test_from_hex() {
+        assert_eq!(Value::from_hex("010203"), Ok(Value::from(vec![1u8, 2, 3])));
+        assert!(matches!(
+            Value::from_hex("0g"),
+            Err(ComradeError::Decode(_))
+        ));
+        assert!(matches!(
+            Value::from_hex("012"),
+            Err(ComradeError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_base64() {
+        assert_eq!(Value::from_base64("emln"), Ok(Value::from(b"zig".to_vec())));
+        assert!(matches!(
+            Value::from_base64("not base64!"),
+            Err(ComradeError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_map_into_dynamic() {
+        let map = Value::Map(HashMap::from([
+            ("alg".to_string(), Value::from("ed25519")),
+            ("sig".to_string(), Value::from(vec![1u8, 2])),
+        ]));
+        assert_eq!(map.len(), "alg".len() + "ed25519".len() + "sig".len() + 2);
+
+        let dynamic: rhai::Dynamic = map.into();
+        let fields = dynamic.cast::<rhai::Map>();
+        assert_eq!(fields["alg"].clone().into_string().unwrap(), "ed25519");
+        assert!(fields["sig"].is_blob());
+    }
+
+    #[test]
+    fn test_bin_clone_shares_data() {
+        let value = Value::from(vec![7u8; 1024]);
+        let cloned = value.clone();
+
+        let (Value::Bin { data: a, .. }, Value::Bin { data: b, .. }) = (&value, &cloned) else {
+            panic!("expected binary values");
+        };
+        assert!(Arc::ptr_eq(a, b));
+        assert_eq!(value, cloned);
+        assert_eq!(cloned.len(), 1024);
+    }
+
+    #[test]
+    fn test_success_into_dynamic() {
+        let dynamic: rhai::Dynamic = Value::Success(3).into();
+        assert_eq!(dynamic.as_int(), Ok(3));
+
+        let dynamic: rhai::Dynamic = Value::Success(usize::MAX).into();
+        assert_eq!(dynamic.as_int(), Ok(rhai::INT::MAX));
+    }
+
+    #[test]
+    fn test_from_dynamic() {
+        assert_eq!(
+            Value::from(rhai::Dynamic::from_blob(vec![1, 2])),
+            Value::from(vec![1u8, 2])
+        );
+        assert_eq!(
+            Value::from(rhai::Dynamic::from("zig".to_string())),
+            Value::from("zig")
+        );
+        assert_eq!(Value::from(rhai::Dynamic::from(true)), Value::from("true"));
+
+        let map = Value::Map(HashMap::from([("alg".to_string(), Value::from("ed25519"))]));
+        let dynamic: rhai::Dynamic = map.clone().into();
+        assert_eq!(Value::from(dynamic), map);
+    }
+
+    #[test]
+    fn test_time_values() {
+        let timestamp = Value::Timestamp(1_700_000_000);
+        assert_ne!(timestamp, Value::Duration(1_700_000_000));
+        assert!(timestamp.is_empty());
+
+        let dynamic: rhai::Dynamic = timestamp.into();
+        assert_eq!(dynamic.as_int(), Ok(1_700_000_000));
+
+        let dynamic: rhai::Dynamic = Value::Duration(u64::MAX).into();
+        assert_eq!(dynamic.into_string().unwrap(), u64::MAX.to_string());
+
+        // a value carried by a dynamic, as made by timestamp() and duration(), converts back
+        let dynamic = rhai::Dynamic::from(Value::Duration(60));
+        assert_eq!(Value::from(dynamic), Value::Duration(60));
+    }
+}
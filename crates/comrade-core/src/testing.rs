@@ -0,0 +1,36 @@
+//! Testing module, with fixtures for writing lock tests
+//!
+//! Generates keys and signatures so downstream crates don't each need their own helpers.
+//! The helpers panic on failure, as they are meant for tests only.
+use multikey::{mk, Multikey, Views as _};
+
+pub use multicodec::Codec;
+
+/// Generates a random private [Multikey] of the given key codec, such as [Codec::Ed25519Priv]
+pub fn gen_keypair(codec: Codec) -> Multikey {
+    let mut rng = rand::rngs::OsRng;
+    mk::Builder::new_from_random_bytes(codec, &mut rng)
+        .expect("key generation failed")
+        .with_comment("test key")
+        .try_build()
+        .expect("key generation failed")
+}
+
+/// Signs the message with the private [Multikey], returning the encoded multisig bytes
+pub fn sign(mk: &Multikey, msg: impl AsRef<[u8]>) -> Vec<u8> {
+    mk.sign_view()
+        .expect("key can't sign")
+        .sign(msg.as_ref(), false, None)
+        .expect("signing failed")
+        .into()
+}
+
+/// The hex encoded public [Multikey] of the private [Multikey]
+pub fn pubkey_hex(mk: &Multikey) -> String {
+    let pk = mk
+        .conv_view()
+        .expect("key can't convert")
+        .to_public_key()
+        .expect("public key conversion failed");
+    hex::encode(Into::<Vec<u8>>::into(pk))
+}
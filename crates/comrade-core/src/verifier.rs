@@ -0,0 +1,16 @@
+//! Verifier module
+use crate::{ComradeError, Value};
+use std::fmt::Debug;
+
+/// Verifies signatures for `check_signature`, in place of the built-in [multikey] verification.
+///
+/// Use this to delegate verification to, for example, an HSM or a remote signer. Set one with
+/// [crate::ComradeBuilder::with_verifier].
+///
+/// Verification must be deterministic: within one lock attempt, a result is reused for any
+/// later check of the same key, message and signature.
+pub trait Verifier: Debug + Send + Sync {
+    /// Verify the signature over the message with the key, both as stored. Returns `Ok(false)`
+    /// when the signature does not verify, and an error when verification could not be attempted.
+    fn verify(&self, key: &Value, msg: &[u8], sig: &Value) -> Result<bool, ComradeError>;
+}
@@ -1,24 +1,83 @@
-use multicodec::Codec;
-use multikey::mk;
-use multikey::Views as _;
+use comrade_core::testing::{gen_keypair, pubkey_hex, sign, Codec};
+use comrade_core::{
+    ComradeBuilder, ComradeError, ContextPairs, Current, MessageEncoding, MessageSource, Pairs,
+    Proposed, Stack, Value, Verifier,
+};
+use std::error::Error;
 
 // Make a random pubkey and print out a pubkey in hex, and the multisignature of the given arg
 fn make_pubkey(msg: impl AsRef<[u8]>) -> (String, String) {
-    let mut rng = rand::rngs::OsRng;
-    let mk = mk::Builder::new_from_random_bytes(Codec::Ed25519Priv, &mut rng)
-        .unwrap()
-        .with_comment("test key")
-        .try_build()
-        .unwrap();
-    let signmk = mk.sign_view().unwrap();
-    let signature = signmk.sign(msg.as_ref(), false, None).unwrap();
+    let mk = gen_keypair(Codec::Ed25519Priv);
+    (pubkey_hex(&mk), hex::encode(sign(&mk, msg)))
+}
+
+const UNLOCK: &str = r#"
+    push("/entry/");
+    push("/entry/proof");
+"#;
+
+const ENTRY: &[u8] = b"for great justice, move every zig!";
+
+/// Proposed store holding the [ENTRY] and its signature proof
+fn proposed_with_proof(sig: &str) -> ContextPairs {
+    let mut proposed = ContextPairs::default();
+    proposed.put("/entry/", &ENTRY.to_vec().into());
+    proposed.put("/entry/proof", &hex::decode(sig).unwrap().into());
+    proposed
+}
+
+#[test]
+fn test_testing_helpers() -> Result<(), Box<dyn Error>> {
+    let mk = gen_keypair(Codec::Ed25519Priv);
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey_hex(&mk))?.into());
+
+    let mut proposed = ContextPairs::default();
+    proposed.put("/entry/", &ENTRY.to_vec().into());
+    proposed.put("/entry/proof", &sign(&mk, ENTRY).into());
 
-    let s: Vec<u8> = signature.into();
-    let sig = hex::encode(s);
-    let conv = mk.conv_view().unwrap();
-    let pk = conv.to_public_key().unwrap();
-    let pubkey = hex::encode(Into::<Vec<u8>>::into(pk.clone()));
-    (pubkey, sig)
+    let unlocked =
+        ComradeBuilder::new(UNLOCK, Current(current), Proposed(proposed)).try_unlock()?;
+
+    assert_eq!(
+        unlocked.try_lock(r#"check_signature("/pubkey", "/entry/")"#)?,
+        Some(Value::Success(0))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_lenient_hex_pubkey() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+
+    // store the pubkey as a hex string instead of binary
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &pubkey.into());
+
+    let lock = r#"check_signature("/pubkey", "/entry/")"#.to_string();
+
+    let lenient = ComradeBuilder::new(
+        UNLOCK,
+        Current(current.clone()),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .with_lenient_hex()
+    .try_unlock()?;
+
+    assert_eq!(lenient.try_lock(lock.clone())?, Some(Value::Success(0)));
+
+    let strict = ComradeBuilder::new(
+        UNLOCK,
+        Current(current),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .try_unlock()?;
+
+    assert!(matches!(strict.try_lock(lock)?, Some(Value::Failure(_))));
+
+    Ok(())
 }
 //#[test]
 //fn test_lib_pubkey() -> Result<(), Box<dyn Error>> {
@@ -98,3 +157,595 @@ fn make_pubkey(msg: impl AsRef<[u8]>) -> (String, String) {
 //
 //    Ok(())
 //}
+
+#[test]
+fn test_message_source() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+
+    // the signed message also lives alongside the pubkey in the current store
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey)?.into());
+    current.put("/signed", &ENTRY.to_vec().into());
+
+    let proposed_lock = r#"check_signature("/pubkey", "/entry/")"#.to_string();
+    let current_lock = r#"check_signature("/pubkey", "/signed")"#.to_string();
+
+    let from_proposed = ComradeBuilder::new(
+        UNLOCK,
+        Current(current.clone()),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .try_unlock()?;
+
+    assert_eq!(
+        from_proposed.try_lock(proposed_lock.clone())?,
+        Some(Value::Success(0))
+    );
+    assert!(matches!(
+        from_proposed.try_lock(current_lock.clone())?,
+        Some(Value::Failure(_))
+    ));
+
+    let from_current = ComradeBuilder::new(
+        UNLOCK,
+        Current(current),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .with_message_source(MessageSource::Current)
+    .try_unlock()?;
+
+    assert_eq!(
+        from_current.try_lock(current_lock)?,
+        Some(Value::Success(0))
+    );
+    assert!(matches!(
+        from_current.try_lock(proposed_lock)?,
+        Some(Value::Failure(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_message_encoding() -> Result<(), Box<dyn Error>> {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+    // the signature is over the same bytes, whichever way the message is stored
+    let entry = [0xde, 0xad, 0xbe, 0xef, 0x00, 0xff];
+    let (pubkey, sig) = make_pubkey(entry);
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey)?.into());
+
+    for (encoding, message) in [
+        (MessageEncoding::Raw, Value::from(entry.to_vec())),
+        (MessageEncoding::Hex, Value::from(hex::encode(entry))),
+        (MessageEncoding::Base64, Value::from(STANDARD.encode(entry))),
+    ] {
+        let mut proposed = proposed_with_proof(&sig);
+        proposed.put("/entry/", &message);
+
+        let unlocked =
+            ComradeBuilder::new(UNLOCK, Current(current.clone()), Proposed(proposed.clone()))
+                .with_message_encoding(encoding)
+                .try_unlock()?;
+        assert_eq!(
+            unlocked.try_lock(r#"check_signature("/pubkey", "/entry/")"#)?,
+            Some(Value::Success(0)),
+            "{encoding:?}"
+        );
+
+        // without the encoding, string messages are used as raw bytes and don't verify
+        if encoding != MessageEncoding::Raw {
+            let unlocked =
+                ComradeBuilder::new(UNLOCK, Current(current.clone()), Proposed(proposed))
+                    .try_unlock()?;
+            assert!(matches!(
+                unlocked.try_lock(r#"check_signature("/pubkey", "/entry/")"#)?,
+                Some(Value::Failure(_))
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_try_lock_with_current() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+    let (other_pubkey, _) = make_pubkey(ENTRY);
+
+    let mut signer = ContextPairs::default();
+    signer.put("/pubkey", &hex::decode(pubkey)?.into());
+
+    let mut other = ContextPairs::default();
+    other.put("/pubkey", &hex::decode(other_pubkey)?.into());
+
+    let unlocked = ComradeBuilder::new(
+        UNLOCK,
+        Current(signer.clone()),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .try_unlock()?;
+
+    let lock = r#"check_signature("/pubkey", "/entry/")"#;
+
+    assert_eq!(
+        unlocked.try_lock_with_current(lock, Current(signer))?,
+        Some(Value::Success(0))
+    );
+    assert!(matches!(
+        unlocked.try_lock_with_current(lock, Current(other))?,
+        Some(Value::Failure(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_check_signature_branch() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+
+    // the pubkey is scoped to the fork
+    let mut current = ContextPairs::default();
+    current.put("/forks/child/pubkey", &hex::decode(pubkey)?.into());
+
+    let unlocked = ComradeBuilder::new(
+        UNLOCK,
+        Current(current),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .with_domain("/forks/child/")
+    .try_unlock()?;
+
+    assert_eq!(
+        unlocked.try_lock(r#"check_signature_branch("pubkey", "/entry/")"#)?,
+        Some(Value::Success(0))
+    );
+    assert!(matches!(
+        unlocked.try_lock(r#"check_signature("pubkey", "/entry/")"#)?,
+        Some(Value::Failure(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_try_lock_over_domains() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+    let (other, _) = make_pubkey(ENTRY);
+
+    // only the pubkey under fork b made the signature
+    let mut current = ContextPairs::default();
+    current.put("/forks/a/pubkey", &hex::decode(other)?.into());
+    current.put("/forks/b/pubkey", &hex::decode(pubkey)?.into());
+
+    let unlocked = ComradeBuilder::new(
+        UNLOCK,
+        Current(current),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .try_unlock()?;
+
+    let lock = r#"check_signature_branch("pubkey", "/entry/")"#;
+    let domains = ["/forks/a/".to_string(), "/forks/b/".to_string()];
+
+    assert_eq!(
+        unlocked.try_lock_over_domains(lock, &domains)?,
+        Some(("/forks/b/".to_string(), Value::Success(0)))
+    );
+    assert_eq!(unlocked.try_lock_over_domains(lock, &domains[..1])?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_swapped_stores_hint() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+
+    // the pubkey was put in the proposed store instead of the current store
+    let mut proposed = proposed_with_proof(&sig);
+    proposed.put("/pubkey", &hex::decode(pubkey)?.into());
+
+    let unlocked =
+        ComradeBuilder::new(UNLOCK, Current(ContextPairs::default()), Proposed(proposed))
+            .try_unlock()?;
+
+    let Some(Value::Failure(e)) = unlocked.try_lock(r#"check_signature("/pubkey", "/entry/")"#)?
+    else {
+        panic!("expected the signature check to fail");
+    };
+    assert!(e.contains("stores swapped"), "{e}");
+
+    // a key missing from both stores gets no hint
+    let Some(Value::Failure(e)) = unlocked.try_lock(r#"check_signature("/missing", "/entry/")"#)?
+    else {
+        panic!("expected the signature check to fail");
+    };
+    assert_eq!(e, "no multikey associated with /missing");
+
+    Ok(())
+}
+
+#[test]
+fn test_check_signature_chain() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+    let entry_hash: Vec<u8> = multihash::mh::Builder::new_from_bytes(Codec::Sha2256, ENTRY)
+        .unwrap()
+        .try_build()
+        .unwrap()
+        .into();
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey)?.into());
+    current.put("/entry-hash", &entry_hash.into());
+    current.put("/other-hash", &b"not the hash".to_vec().into());
+
+    let unlocked = ComradeBuilder::new(
+        UNLOCK,
+        Current(current),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .try_unlock()?;
+
+    assert_eq!(
+        unlocked.try_lock(
+            r#"check_signature_chain("/pubkey", "/entry/") && check_eq("/entry-hash")"#
+        )?,
+        Some(Value::Success(0))
+    );
+    assert!(matches!(
+        unlocked.try_lock(
+            r#"check_signature_chain("/pubkey", "/entry/") && check_eq("/other-hash")"#
+        )?,
+        Some(Value::Failure(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_check_signature_over_proposed() -> Result<(), Box<dyn Error>> {
+    let mut proposed = ContextPairs::default();
+    proposed.put("/entry/", &ENTRY.to_vec().into());
+    let (pubkey, sig) = make_pubkey(proposed.canonical_bytes(&[]).unwrap());
+    proposed.put("/entry/proof", &hex::decode(sig)?.into());
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey)?.into());
+
+    let lock = r#"check_signature_over_proposed("/pubkey", "/entry/proof")"#;
+    let unlocked =
+        ComradeBuilder::new(UNLOCK, Current(current.clone()), Proposed(proposed.clone()))
+            .try_unlock()?;
+    assert_eq!(unlocked.try_lock(lock)?, Some(Value::Success(0)));
+
+    // a key the signature doesn't cover
+    proposed.put("/unsigned", &b"slipped in".to_vec().into());
+    let unlocked =
+        ComradeBuilder::new(UNLOCK, Current(current), Proposed(proposed)).try_unlock()?;
+    assert!(matches!(unlocked.try_lock(lock)?, Some(Value::Failure(_))));
+
+    Ok(())
+}
+
+#[test]
+fn test_put_before_unlock() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey)?.into());
+
+    let unlocked = ComradeBuilder::new(UNLOCK, Current(current), Proposed(ContextPairs::default()))
+        .put("/entry/", ENTRY.to_vec().into())
+        .put_many(vec![
+            ("/entry/proof".to_string(), hex::decode(sig)?.into()),
+            ("/entry/hint".to_string(), "zag".into()),
+        ])
+        .try_unlock()?;
+
+    assert_eq!(unlocked.proposed_len(), 3);
+    assert_eq!(
+        unlocked.try_lock(r#"check_signature("/pubkey", "/entry/")"#)?,
+        Some(Value::Success(0))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_combined() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey)?.into());
+
+    let script = r#"
+        push("/entry/proof");
+        check_signature("/pubkey", "/entry/")
+    "#;
+
+    let combined = ComradeBuilder::new(
+        script,
+        Current(current.clone()),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .combined()
+    .try_unlock()?;
+    assert_eq!(combined.returns().top(), Some(Value::Success(0)));
+    assert!(combined.pstack().is_empty());
+
+    // without combined mode, the check functions aren't available to the unlock script
+    assert!(matches!(
+        ComradeBuilder::new(
+            script,
+            Current(current),
+            Proposed(proposed_with_proof(&sig))
+        )
+        .try_unlock(),
+        Err(ComradeError::Eval(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_shared_stores() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey)?.into());
+
+    let locks = [
+        r#"check_signature("/ephemeral", "/entry/")"#,
+        r#"check_signature("/pubkey", "/entry/")"#,
+        r#"check_signature("/ephemeral", "/entry/") || check_signature("/pubkey", "/entry/")"#,
+    ];
+
+    let deep = ComradeBuilder::new(
+        UNLOCK,
+        Current(current.clone()),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .try_unlock()?;
+
+    let shared = ComradeBuilder::new(
+        UNLOCK,
+        Current(current),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .with_shared_stores()
+    .try_unlock()?;
+
+    for lock in locks {
+        assert_eq!(deep.try_lock(lock)?, shared.try_lock(lock)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_signature_stackkey() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+
+    // the pubkey is disclosed at unlock time instead of being stored in current
+    let mut proposed = proposed_with_proof(&sig);
+    proposed.put("/disclosed", &hex::decode(pubkey)?.into());
+
+    let unlock = r#"
+        push("/entry/proof");
+        push("/disclosed");
+    "#;
+
+    let unlocked =
+        ComradeBuilder::new(unlock, Current(ContextPairs::default()), Proposed(proposed))
+            .try_unlock()?;
+
+    assert_eq!(
+        unlocked.try_lock(r#"check_signature_stackkey("/entry/")"#)?,
+        Some(Value::Success(0))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_check_signature_any_msg() -> Result<(), Box<dyn Error>> {
+    // the signature covers the new serialization only
+    let (pubkey, sig) = make_pubkey(ENTRY);
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey)?.into());
+
+    let mut proposed = proposed_with_proof(&sig);
+    proposed.put("/entry/v1", &b"move zig".to_vec().into());
+    proposed.put("/entry/v2", &ENTRY.to_vec().into());
+
+    let unlocked =
+        ComradeBuilder::new(UNLOCK, Current(current), Proposed(proposed)).try_unlock()?;
+
+    assert_eq!(
+        unlocked.try_lock(r#"check_signature_any_msg("/pubkey", ["/entry/v1", "/entry/v2"])"#)?,
+        Some(Value::Success(0))
+    );
+    assert!(matches!(
+        unlocked.try_lock(r#"check_signature_any_msg("/pubkey", ["/entry/v1"])"#)?,
+        Some(Value::Failure(_))
+    ));
+
+    // elements that aren't strings are an error, not a silent miss
+    assert!(matches!(
+        unlocked.try_lock(r#"check_signature_any_msg("/pubkey", ["/entry/v2", 42])"#),
+        Err(ComradeError::Eval(e)) if e.contains("expects an array of strings, found i32")
+    ));
+    assert!(matches!(
+        unlocked.try_lock(r#"check_preimage_any(["/hash", true])"#),
+        Err(ComradeError::Eval(e)) if e.contains("expects an array of strings, found bool")
+    ));
+
+    Ok(())
+}
+
+/// Approves any signature made with the one trusted key
+#[derive(Debug)]
+struct MockVerifier;
+
+impl Verifier for MockVerifier {
+    fn verify(&self, key: &Value, msg: &[u8], _sig: &Value) -> Result<bool, ComradeError> {
+        assert_eq!(msg, ENTRY);
+        Ok(key == &Value::from("trusted"))
+    }
+}
+
+#[test]
+fn test_with_verifier() -> Result<(), Box<dyn Error>> {
+    let mut current = ContextPairs::default();
+    current.put("/trusted", &"trusted".into());
+    current.put("/untrusted", &"untrusted".into());
+
+    let mut proposed = ContextPairs::default();
+    proposed.put("/entry/", &ENTRY.to_vec().into());
+    proposed.put("/entry/proof", &b"not a multisig".to_vec().into());
+
+    let unlocked = ComradeBuilder::new(UNLOCK, Current(current), Proposed(proposed))
+        .with_verifier(MockVerifier)
+        .try_unlock()?;
+
+    assert_eq!(
+        unlocked.try_lock(r#"check_signature("/trusted", "/entry/")"#)?,
+        Some(Value::Success(0))
+    );
+    assert_eq!(
+        unlocked.try_lock(r#"check_signature("/untrusted", "/entry/")"#)?,
+        Some(Value::Failure("signature did not verify".to_string()))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_try_lock_signature() -> Result<(), Box<dyn Error>> {
+    let (pubkey, sig) = make_pubkey(ENTRY);
+    let (other, _) = make_pubkey(ENTRY);
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey)?.into());
+    current.put("/other", &hex::decode(other)?.into());
+
+    let unlocked = ComradeBuilder::new(
+        UNLOCK,
+        Current(current),
+        Proposed(proposed_with_proof(&sig)),
+    )
+    .try_unlock()?;
+
+    for key in ["/pubkey", "/other", "/missing"] {
+        let script = format!(r#"check_signature("{key}", "/entry/")"#);
+        assert_eq!(
+            unlocked.try_lock_signature(key, "/entry/"),
+            unlocked.try_lock(script)?
+        );
+    }
+    assert_eq!(
+        unlocked.try_lock_signature("/pubkey", "/entry/"),
+        Some(Value::Success(0))
+    );
+
+    Ok(())
+}
+
+/// Fails every verification with an error, counting how many verifications were attempted
+#[derive(Debug, Default)]
+struct CountingVerifier(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+impl Verifier for CountingVerifier {
+    fn verify(&self, _key: &Value, _msg: &[u8], _sig: &Value) -> Result<bool, ComradeError> {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Err(ComradeError::Decode("malformed signature".to_string()))
+    }
+}
+
+#[test]
+fn test_signature_cache() -> Result<(), Box<dyn Error>> {
+    let verifier = CountingVerifier::default();
+    let calls = std::sync::Arc::clone(&verifier.0);
+
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &"pubkey".into());
+
+    let mut proposed = ContextPairs::default();
+    proposed.put("/entry/", &ENTRY.to_vec().into());
+    proposed.put("/entry/proof", &b"sig".to_vec().into());
+
+    let unlocked = ComradeBuilder::new(UNLOCK, Current(current), Proposed(proposed))
+        .with_verifier(verifier)
+        .try_unlock()?;
+
+    let lock = r#"check_signature("/pubkey", "/entry/") || check_signature("/pubkey", "/entry/")"#;
+
+    // the second, identical check reuses the first verification, and fails with its error
+    assert_eq!(
+        unlocked.try_lock(lock)?,
+        Some(Value::Failure(
+            "decoding failed: malformed signature".to_string()
+        ))
+    );
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    // but each lock attempt verifies afresh
+    unlocked.try_lock(lock)?;
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+/// Collects the formatted trace output in memory
+#[derive(Clone, Default)]
+struct Capture(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_call_tracing() -> Result<(), Box<dyn Error>> {
+    use test_log::tracing_subscriber;
+
+    let (pubkey, sig) = make_pubkey(ENTRY);
+    let mut current = ContextPairs::default();
+    current.put("/pubkey", &hex::decode(pubkey)?.into());
+
+    let capture = Capture::default();
+    let writer = capture.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+
+    tracing::subscriber::with_default(subscriber, || -> Result<(), Box<dyn Error>> {
+        let unlocked = ComradeBuilder::new(
+            UNLOCK,
+            Current(current),
+            Proposed(proposed_with_proof(&sig)),
+        )
+        .with_call_tracing()
+        .try_unlock()?;
+
+        unlocked.try_lock(r#"check_signature("/pubkey", "/entry/")"#)?;
+        Ok(())
+    })?;
+
+    let output = String::from_utf8(capture.0.lock().unwrap().clone())?;
+    assert!(output.contains("push(/entry/proof) -> true"));
+    assert!(output.contains("check_signature(/pubkey, /entry/) -> true"));
+
+    Ok(())
+}